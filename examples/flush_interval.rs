@@ -0,0 +1,13 @@
+#[macro_use]
+extern crate mhlog;
+
+use std::time::Duration;
+
+fn main() {
+    let _guard = mhlog::flush_on_drop();
+    mhlog::set_min_flush_interval(Duration::from_millis(100));
+    for i in 0..1000 {
+        info!("Message {}", i);
+    }
+    info!("Flushed when main returns");
+}
@@ -0,0 +1,9 @@
+#[macro_use]
+extern crate mhlog;
+
+use mhlog::Severity;
+
+fn main() {
+    log!("Hello log world!");
+    log!(Severity::Warn, "Hello {} world!", "warning");
+}
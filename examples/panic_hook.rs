@@ -0,0 +1,8 @@
+extern crate mhlog;
+
+fn main() {
+    mhlog::PanicHookBuilder::new()
+        .chain(false)
+        .install();
+    panic!("Goodbye panicking world!");
+}
@@ -13,6 +13,9 @@
 //! [`verbose!()`]  
 //! [`debug!()`]  
 //!
+//! Each has a `_pretty` variant ([`debug_pretty!()`] etc.) which pretty-prints
+//! a single value using `{:#?}`.
+//!
 //! Usage
 //! -----
 //!
@@ -29,7 +32,7 @@
 //! The prefix of the log messages may be changed by the user:
 //! 
 //! ```rust
-//! # extern crate mhlog
+//! # extern crate mhlog;
 //! # use mhlog::info;
 //! mhlog::info_prefix_str("Info:".to_string());
 //! info!("Hello custom world!");
@@ -40,7 +43,7 @@
 //! Dynamic log prefixes are also supported:
 //! 
 //! ```rust
-//! # extern crate mhlog
+//! # extern crate mhlog;
 //! # use mhlog::info;
 //! mhlog::info_prefix_fn(|| format!("[{}]", "INFO"));
 //! info!("Hello dynamic world!");
@@ -67,6 +70,9 @@
 //! mhlog = { version = "*", features = ["only_stdout"] }
 //! ```
 //!
+//! The features only set the defaults, which can be changed at runtime for
+//! each severity with `set_stderr`.
+//!
 //! ### Coloured log messages
 //!
 //! Coloured log messages can be enabled with the `colours` feature.
@@ -76,7 +82,27 @@
 //! mhlog = { version = "*", features = ["colours"] }
 //! ```
 //!
+//! By default messages are only coloured when writing to a terminal. This can
+//! be changed at runtime with `set_color_mode`.
+//!
+//! Alternatively the `simple-colors` feature colours messages with raw ANSI
+//! escape codes, configurable with `set_ansi_color`, without any extra
+//! dependencies. If both features are enabled, as with `--all-features`,
+//! `colours` takes precedence and `simple-colors` has no effect.
+//!
+//! ### Stripping debug messages
+//!
+//! The `release-strip-debug` feature makes [`debug!()`] expand to nothing in
+//! release builds, so debug logging has no cost at all. Debug messages in
+//! release builds are then completely invisible, even with `set_debug(true)`.
+//!
+//! ```toml
+//! [dependencies]
+//! mhlog = { version = "*", features = ["release-strip-debug"] }
+//! ```
+//!
 //! [`debug!()`]: macro.debug.html
+//! [`debug_pretty!()`]: macro.debug_pretty.html
 //! [`verbose!()`]: macro.verbose.html
 //! [`info!()`]: macro.info.html
 //! [`warn!()`]: macro.warn.html
@@ -87,26 +113,210 @@ extern crate lazy_static;
 #[cfg(feature = "colours")]
 extern crate console;
 
-use std::sync::RwLock;
+use std::collections::HashMap;
+use std::io::{stderr, stdout, BufWriter, Stderr, Stdout};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 lazy_static! {
     static ref PREFIXES: RwLock<LogPrefixes> = RwLock::new(LogPrefixes::new());
-    static ref VERBOSE: RwLock<bool> = RwLock::new(false);
+    static ref VERBOSE_LEVEL: RwLock<u32> = RwLock::new(0);
     static ref DEBUG: RwLock<bool> = RwLock::new(false);
+    static ref QUIET: RwLock<bool> = RwLock::new(false);
+    static ref FATAL_EXIT_FN: RwLock<Option<fn()>> = RwLock::new(None);
+    static ref PADDING: RwLock<PaddingMode> = RwLock::new(PaddingMode::None);
+    static ref QUIET_PERIOD: RwLock<Option<(Duration, Duration)>> = RwLock::new(None);
+    /// The width prefixes are padded to, and the `PREFIX_GEN` it was
+    /// computed for.
+    static ref PAD_WIDTH: Mutex<(u64, usize)> = Mutex::new((0, 0));
+    static ref LEVEL_ENV_VAR: RwLock<Option<&'static str>> = RwLock::new(None);
+    static ref DEBUG_CALLBACK: RwLock<Option<fn(bool)>> = RwLock::new(None);
+    static ref VERBOSE_CALLBACK: RwLock<Option<fn(bool)>> = RwLock::new(None);
+    static ref SUPPRESSED: RwLock<[bool; 5]> = RwLock::new([false; 5]);
+    static ref STDERR_TABLE: RwLock<[bool; 5]> = {
+        use Severity::*;
+        let mut table = [false; 5];
+        for sev in &[Err, Warn, Info, Verbose, Debug] {
+            table[sev.index()] = sev.default_to_stderr();
+        }
+        RwLock::new(table)
+    };
+    static ref OUTPUT_TARGET: RwLock<OutputTarget> = RwLock::new(OutputTarget::Mixed);
+    static ref INTERCEPTORS: RwLock<Vec<Box<dyn Interceptor>>> = RwLock::new(Vec::new());
+    static ref MESSAGE_TRANSFORM: RwLock<Option<MessageTransform>> = RwLock::new(None);
+    static ref ONCE_PER_KEYS: RwLock<std::collections::HashSet<(std::any::TypeId, u64)>> = RwLock::new(Default::default());
+    static ref EPOCH: std::time::Instant = std::time::Instant::now();
+    static ref COMPACT: RwLock<bool> = RwLock::new(false);
+    static ref DEPRECATION_SEVERITY: RwLock<Severity> = RwLock::new(Severity::Warn);
+    static ref ERROR_CODES: RwLock<HashMap<u32, &'static str>> = RwLock::new(HashMap::new());
+    static ref CHECKPOINT_SEVERITY: RwLock<Severity> = RwLock::new(Severity::Debug);
+    static ref REDIRECTS: RwLock<HashMap<Severity, Severity>> = RwLock::new(HashMap::new());
+    static ref FLUSH_INTERVAL: RwLock<Duration> = RwLock::new(Duration::from_secs(0));
+    static ref STDOUT_BUF: Arc<Mutex<BufWriter<Stdout>>> = Arc::new(Mutex::new(BufWriter::new(stdout())));
+    static ref STDERR_BUF: Arc<Mutex<BufWriter<Stderr>>> = Arc::new(Mutex::new(BufWriter::new(stderr())));
+    static ref WRITERS: Mutex<WriterGroup> = Mutex::new(WriterGroup::default());
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+    static ref ACTIVE_WRITER: Mutex<Option<Box<dyn std::io::Write + Send + Sync>>> = Mutex::new(None);
+    static ref TARGETS: RwLock<HashMap<String, Logger>> = RwLock::new(HashMap::new());
+    static ref GLOBAL_FIELDS: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    static ref IO_ERROR_COUNTS: RwLock<HashMap<std::io::ErrorKind, AtomicU64>> = RwLock::new(HashMap::new());
+    static ref FORMATTER: RwLock<Option<Box<dyn Formatter>>> = RwLock::new(None);
+}
+
+#[cfg(feature = "simple-colors")]
+lazy_static! {
+    static ref ANSI_COLORS: RwLock<HashMap<Severity, &'static str>> = {
+        use Severity::*;
+        let map = [Err, Warn, Debug].iter()
+            .map(|&sev| (sev, sev.color_ansi_escape()))
+            .collect();
+        RwLock::new(map)
+    };
+}
+
+#[cfg(feature = "encoding")]
+lazy_static! {
+    static ref ENCODING: RwLock<Encoding> = RwLock::new(Encoding::Utf8);
+}
+
+#[cfg(feature = "colours")]
+lazy_static! {
+    static ref COLOR_MODE: RwLock<ColorMode> = RwLock::new(ColorMode::Auto);
+    static ref EXT_COLORS: RwLock<HashMap<Severity, ExtColor>> = RwLock::new(HashMap::new());
+    static ref VERBOSE_STYLE: RwLock<console::Style> = RwLock::new(console::Style::new().cyan());
+    /// Terminal support for (true colour, 256 colours).
+    static ref COLOR_SUPPORT: (bool, bool) = {
+        let truecolor = std::env::var("COLORTERM")
+            .map(|v| v == "truecolor" || v == "24bit")
+            .unwrap_or(false);
+        let c256 = truecolor || std::env::var("TERM")
+            .map(|v| v.contains("256color"))
+            .unwrap_or(false);
+        (truecolor, c256)
+    };
+    /// Whether (stdout, stderr) are terminals.
+    static ref IS_TERMINAL: (bool, bool) = {
+        use std::io::IsTerminal;
+        (stdout().is_terminal(), stderr().is_terminal())
+    };
+}
+
+#[cfg(feature = "colours")]
+static COLOR_STRIP_ON_FILE: AtomicBool = AtomicBool::new(true);
+
+type PanicHook = Arc<dyn Fn(&std::panic::PanicHookInfo) + Sync + Send + 'static>;
+
+lazy_static! {
+    static ref PREV_PANIC_HOOK: Mutex<Option<PanicHook>> = Mutex::new(None);
+}
+
+thread_local! {
+    static CTX: std::cell::RefCell<LogContext> = std::cell::RefCell::new(LogContext::default());
+    static LAST_CHECKPOINT: std::cell::Cell<Option<std::time::Instant>> = const { std::cell::Cell::new(None) };
+    static THREAD_PREFIX: std::cell::RefCell<HashMap<Severity, String>> = std::cell::RefCell::new(HashMap::new());
+    static INDENT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static GROUP_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static SCOPE_BUF: std::cell::RefCell<Option<Vec<ScopedMsg>>> = const { std::cell::RefCell::new(None) };
+    static ENV_LEVEL: std::cell::Cell<EnvLevelCache> = const { std::cell::Cell::new(None) };
+}
+
+/// The level read from the level environment variable: when it was read,
+/// the variable name, and the level if the variable was set and valid.
+type EnvLevelCache = Option<(std::time::Instant, &'static str, Option<LogLevel>)>;
+
+static FLUSHER_RUNNING: AtomicBool = AtomicBool::new(false);
+static NULL_OUTPUT: AtomicBool = AtomicBool::new(false);
+/// Incremented whenever the prefixes, compact mode or padding change, to
+/// invalidate `PAD_WIDTH`.
+static PREFIX_GEN: AtomicU64 = AtomicU64::new(1);
+#[cfg(feature = "test-utils")]
+thread_local! {
+    static THREAD_COUNTS: std::cell::Cell<[u64; 5]> = const { std::cell::Cell::new([0; 5]) };
+}
+#[cfg(feature = "test-utils")]
+static CAPTURE_COUNTS: [AtomicU64; 5] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+
+#[cfg(feature = "metrics")]
+static MESSAGE_COUNTS: [AtomicU64; 5] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+#[cfg(feature = "udp-syslog")]
+lazy_static! {
+    static ref UDP_SYSLOG: RwLock<Option<UdpSyslog>> = RwLock::new(None);
+}
+
+#[cfg(feature = "metrics")]
+lazy_static! {
+    static ref COUNTS_RESET: RwLock<std::time::Instant> = RwLock::new(std::time::Instant::now());
+}
+
+#[cfg(feature = "test-utils")]
+lazy_static! {
+    static ref TEST_LOCK: TestLock = TestLock::default();
+    /// The installed mock loggers, innermost last.
+    static ref MOCKS: RwLock<Vec<MockLogger>> = RwLock::new(Vec::new());
 }
 
+static HEX_ROW_WIDTH: AtomicUsize = AtomicUsize::new(16);
+static SECTION_WIDTH: AtomicUsize = AtomicUsize::new(60);
+
+static ERR_EXIT_CODE: AtomicI32 = AtomicI32::new(1);
+
+static MESSAGE_IDS: AtomicBool = AtomicBool::new(false);
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+static LAST_MESSAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+static SAMPLE_RATES: [AtomicU32; 5] = [
+    AtomicU32::new(1), AtomicU32::new(1), AtomicU32::new(1), AtomicU32::new(1), AtomicU32::new(1),
+];
+static SAMPLE_COUNTS: [AtomicU64; 5] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+static SAMPLE_SKIPPED: [AtomicU64; 5] = [
+    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+];
+
 /// Print a message with the error prefix.
 /// 
 /// By default `err` will write to stderr. This can be changed with the `only_stdout` feature.
 /// 
 /// To change the error prefix use [`error_prefix_str`] or [`error_prefix_fn`].
 /// 
+/// Given an error as `error: <error>` instead of a format string, the
+/// error is printed on one line followed by its chain of sources:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err;
+/// let e = std::fs::read("/does/not/exist").unwrap_err();
+/// err!(error: e); // Prints: [!!] No such file or directory (os error 2)
+/// ```
+/// 
+/// A boxed error, like `Box<dyn Error>`, is printed with `err!(error: *e)`. To
+/// print each source on its own line use [`err_chain!()`].
+/// 
 /// [`error_prefix_fn`]: fn.error_prefix_fn.html
 /// [`error_prefix_str`]: fn.error_prefix_str.html
+/// [`err_chain!()`]: macro.err_chain.html
 #[macro_export]
 macro_rules! err {
+    (target: $target:expr, $($arg:tt)+) => ({
+        $crate::_log_target($crate::Severity::Err, $target, format_args!($($arg)+));
+    });
+    (logger: $logger:expr, $($arg:tt)+) => (
+        $crate::Logger::log(&$logger, $crate::Severity::Err, format_args!($($arg)+));
+    );
+    (error: $e:expr) => (
+        $crate::_log_error($crate::Severity::Err, &$e);
+    );
+    ($fmt:literal $($arg:tt)*) => (
+        $crate::_log($crate::Severity::Err, format_args!($fmt $($arg)*));
+    );
     ($($arg:tt)+) => (
-        $crate::_log($crate::Severity::Err, format!($($arg)+));
+        $crate::_log($crate::Severity::Err, format_args!($($arg)+));
     )
 }
 
@@ -120,8 +330,17 @@ macro_rules! err {
 /// [`warning_prefix_str`]: fn.warning_prefix_str.html
 #[macro_export]
 macro_rules! warn {
+    (target: $target:expr, $($arg:tt)+) => ({
+        $crate::_log_target($crate::Severity::Warn, $target, format_args!($($arg)+));
+    });
+    (logger: $logger:expr, $($arg:tt)+) => (
+        $crate::Logger::log(&$logger, $crate::Severity::Warn, format_args!($($arg)+));
+    );
+    ($fmt:literal $($arg:tt)*) => (
+        $crate::_log($crate::Severity::Warn, format_args!($fmt $($arg)*));
+    );
     ($($arg:tt)+) => (
-        $crate::_log($crate::Severity::Warn, format!($($arg)+));
+        $crate::_log($crate::Severity::Warn, format_args!($($arg)+));
     )
 }
 
@@ -131,12 +350,43 @@ macro_rules! warn {
 /// 
 /// To change the info prefix use [`info_prefix_str`] or [`info_prefix_fn`].
 /// 
+/// All the logging macros optionally take a [`Logger`], given as
+/// `logger: <logger>` before the format string, logging with it instead of
+/// the global configuration:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// let logger = mhlog::Logger::new();
+/// info!(logger: logger, "Hello {}!", "library");
+/// ```
+/// 
+/// Or a target, which is added to the prefix or routed to the logger
+/// registered with [`register_target`]:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// info!(target: "http", "Request received"); // Prints: [*][http] Request received
+/// ```
+/// 
+/// [`Logger`]: struct.Logger.html
+/// [`register_target`]: fn.register_target.html
 /// [`info_prefix_fn`]: fn.info_prefix_fn.html
 /// [`info_prefix_str`]: fn.info_prefix_str.html
 #[macro_export]
 macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => ({
+        $crate::_log_target($crate::Severity::Info, $target, format_args!($($arg)+));
+    });
+    (logger: $logger:expr, $($arg:tt)+) => ({
+        $crate::Logger::log(&$logger, $crate::Severity::Info, format_args!($($arg)+));
+    });
+    ($fmt:literal $($arg:tt)*) => ({
+        $crate::_log($crate::Severity::Info, format_args!($fmt $($arg)*));
+    });
     ($($arg:tt)+) => ({
-        $crate::_log($crate::Severity::Info, format!($($arg)+));
+        $crate::_log($crate::Severity::Info, format_args!($($arg)+));
     })
 }
 
@@ -148,13 +398,31 @@ macro_rules! info {
 /// 
 /// To change the verbose prefix use [`info_prefix_str`] or [`info_prefix_fn`].
 /// 
+/// A message may require a higher verbosity level, set with
+/// [`set_verbose_level`], with `verbose!(level = 2, "message")`.
+/// 
 /// [`set_verbose`]: fn.set_verbose.html
+/// [`set_verbose_level`]: fn.set_verbose_level.html
 /// [`info_prefix_fn`]: fn.info_prefix_fn.html
 /// [`info_prefix_str`]: fn.info_prefix_str.html
 #[macro_export]
 macro_rules! verbose {
+    (target: $target:expr, $($arg:tt)+) => ({
+        $crate::_log_target($crate::Severity::Verbose, $target, format_args!($($arg)+));
+    });
+    (logger: $logger:expr, $($arg:tt)+) => ({
+        $crate::Logger::log(&$logger, $crate::Severity::Verbose, format_args!($($arg)+));
+    });
+    (level = $level:expr, $($arg:tt)+) => ({
+        if $crate::verbose_level() >= $level {
+            $crate::_log($crate::Severity::Verbose, format_args!($($arg)+));
+        }
+    });
+    ($fmt:literal $($arg:tt)*) => ({
+        $crate::_log($crate::Severity::Verbose, format_args!($fmt $($arg)*));
+    });
     ($($arg:tt)+) => ({
-        $crate::_log($crate::Severity::Verbose, format!($($arg)+));
+        $crate::_log($crate::Severity::Verbose, format_args!($($arg)+));
     })
 }
 
@@ -166,198 +434,5835 @@ macro_rules! verbose {
 /// 
 /// To change the debug prefix use [`debug_prefix_str`] or [`debug_prefix_fn`].
 /// 
+/// With the `release-strip-debug` feature, `debug` expands to nothing in
+/// release builds (without `debug_assertions`). The arguments are not even
+/// evaluated, and the messages are completely invisible regardless of
+/// [`set_debug`].
+/// 
 /// [`set_debug`]: fn.set_debug.html
 /// [`debug_prefix_fn`]: fn.debug_prefix_fn.html
 /// [`debug_prefix_str`]: fn.debug_prefix_str.html
+#[cfg(any(not(feature = "release-strip-debug"), debug_assertions))]
 #[macro_export]
 macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => ({
+        $crate::_log_target($crate::Severity::Debug, $target, format_args!($($arg)+));
+    });
+    (logger: $logger:expr, $($arg:tt)+) => ({
+        $crate::Logger::log(&$logger, $crate::Severity::Debug, format_args!($($arg)+));
+    });
+    ($fmt:literal $($arg:tt)*) => ({
+        $crate::_log($crate::Severity::Debug, format_args!($fmt $($arg)*));
+    });
     ($($arg:tt)+) => ({
-        $crate::_log($crate::Severity::Debug, format!($($arg)+));
+        $crate::_log($crate::Severity::Debug, format_args!($($arg)+));
     })
 }
 
-/// Print a message with the error prefix and exit with error code 1.
-/// See [`err!()`]
+/// Stripped [`debug`](macro.debug.html), for release builds with the
+/// `release-strip-debug` feature.
+#[cfg(all(feature = "release-strip-debug", not(debug_assertions)))]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ({});
+}
+
+/// Print a message with the error prefix if `cond` is true.
 /// 
-/// [`err!()`]: macro.err.html
+/// The message is only formatted when the condition is true, so the
+/// format arguments are not evaluated otherwise.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err_if;
+/// # use std::cell::Cell;
+/// let evaluated = Cell::new(false);
+/// let expensive = || { evaluated.set(true); 42 };
+/// err_if!(false, "Value: {}", expensive());
+/// assert!(!evaluated.get());
+/// err_if!(true, "Value: {}", expensive());
+/// assert!(evaluated.get());
+/// ```
 #[macro_export]
-macro_rules! bail {
-    ($($arg:tt)+) => ({
-        $crate::err!($($arg)+);
-        std::process::exit(1);
-    });
+macro_rules! err_if {
+    ($cond:expr, $($arg:tt)+) => ({
+        if $cond {
+            $crate::_log($crate::Severity::Err, format_args!($($arg)+));
+        }
+    })
 }
 
-#[doc(hidden)]
-pub fn _log(severity: Severity, msg: String) {
-    use std::io::{stderr, stdout, Write};
+/// Print a message with the warn prefix if `cond` is true.
+/// See [`err_if!()`]
+/// 
+/// [`err_if!()`]: macro.err_if.html
+#[macro_export]
+macro_rules! warn_if {
+    ($cond:expr, $($arg:tt)+) => ({
+        if $cond {
+            $crate::_log($crate::Severity::Warn, format_args!($($arg)+));
+        }
+    })
+}
 
-    if severity.suppressed() {
-        return
-    }
+/// Print a message with the info prefix if `cond` is true.
+/// See [`err_if!()`]
+/// 
+/// [`err_if!()`]: macro.err_if.html
+#[macro_export]
+macro_rules! info_if {
+    ($cond:expr, $($arg:tt)+) => ({
+        if $cond {
+            $crate::_log($crate::Severity::Info, format_args!($($arg)+));
+        }
+    })
+}
 
-    let txt = format!("{} {}\n", severity.prefix(), msg);
-    #[cfg(feature = "colours")]
-    let txt = severity.style(txt).to_string();
+/// Print a message with the verbose prefix if `cond` is true.
+/// See [`err_if!()`]
+/// 
+/// [`err_if!()`]: macro.err_if.html
+#[macro_export]
+macro_rules! verbose_if {
+    ($cond:expr, $($arg:tt)+) => ({
+        if $cond {
+            $crate::_log($crate::Severity::Verbose, format_args!($($arg)+));
+        }
+    })
+}
 
-    if severity.to_stderr() {
-        let _ = stderr().lock().write_all(txt.as_bytes());
-    } else {
-        let _ = stdout().lock().write_all(txt.as_bytes());
-    }
+/// Print a message with the debug prefix if `cond` is true.
+/// See [`err_if!()`]
+/// 
+/// [`err_if!()`]: macro.err_if.html
+#[macro_export]
+macro_rules! debug_if {
+    ($cond:expr, $($arg:tt)+) => ({
+        if $cond {
+            $crate::_log($crate::Severity::Debug, format_args!($($arg)+));
+        }
+    })
 }
 
-// -----------------------------------------------------------------------------
-// Config
+/// Print a message with [`debug!()`], but only in builds with
+/// `debug_assertions`. In release builds the call expands to nothing, and the
+/// arguments are not evaluated.
+/// 
+/// Unlike the `release-strip-debug` feature, this only affects the
+/// individual call site, and is decided by the build of the calling crate.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::conditional_debug;
+/// # let state = "ready";
+/// conditional_debug!("Internal state: {:?}", state);
+/// ```
+/// 
+/// [`debug!()`]: macro.debug.html
+#[macro_export]
+macro_rules! conditional_debug {
+    ($($arg:tt)+) => ({
+        #[cfg(debug_assertions)]
+        { $crate::debug!($($arg)+); }
+    })
+}
 
-/// Enable/disable debug messages.
+/// Print a message with [`verbose!()`], but only in builds with
+/// `debug_assertions`. See [`conditional_debug!()`]
 /// 
-/// By default debug messages are suppressed.
+/// [`verbose!()`]: macro.verbose.html
+/// [`conditional_debug!()`]: macro.conditional_debug.html
+#[macro_export]
+macro_rules! conditional_verbose {
+    ($($arg:tt)+) => ({
+        #[cfg(debug_assertions)]
+        { $crate::verbose!($($arg)+); }
+    })
+}
+
+/// Print a message with [`debug!()`] if `cond` is false, like a
+/// `debug_assert!` which doesn't panic.
 /// 
-pub fn set_debug(val: bool) {
-    let mut dbg = DEBUG.write().unwrap();
-    *dbg = val;
+/// Only checked in builds with `debug_assertions`. In release builds the
+/// call expands to nothing, and neither `cond` nor the arguments are
+/// evaluated.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::debug_assert_log;
+/// # let queue: Vec<u32> = Vec::new();
+/// debug_assert_log!(queue.len() < 100, "Queue is growing: {} items", queue.len());
+/// ```
+/// 
+/// [`debug!()`]: macro.debug.html
+#[macro_export]
+macro_rules! debug_assert_log {
+    ($cond:expr, $($arg:tt)+) => ({
+        #[cfg(debug_assertions)]
+        {
+            if !$cond {
+                $crate::debug!($($arg)+);
+            }
+        }
+    })
 }
 
-/// Enable/disable verbose messages.
+/// Print a message with an explicit severity, defaulting to info.
 /// 
-/// By default verbose messages are suppressed.
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{mlog, Severity};
+/// mlog!("An info message.");
+/// mlog!(Severity::Warn, "A warning message: {}", 42);
+/// ```
 /// 
-pub fn set_verbose(val: bool) {
-    let mut v = VERBOSE.write().unwrap();
-    *v = val;
+/// With the `log-macro` feature this is also available as `log!()`. It is
+/// not enabled by default, since it conflicts with the `log` crate.
+#[macro_export]
+macro_rules! mlog {
+    ($fmt:literal $($arg:tt)*) => ({
+        $crate::_log($crate::Severity::Info, format_args!($fmt $($arg)*));
+    });
+    ($severity:expr, $($arg:tt)+) => ({
+        $crate::_log($severity, format_args!($($arg)+));
+    })
 }
 
-/// Change the error prefix to a new static value.
-pub fn error_prefix_str(s: String) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.err_str = s;
+/// Alias of [`mlog!()`]. Requires the `log-macro` feature.
+/// 
+/// [`mlog!()`]: macro.mlog.html
+#[cfg(feature = "log-macro")]
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)+) => ({
+        $crate::mlog!($($arg)+);
+    })
 }
 
-/// Change the warning prefix to a new static value.
-pub fn warning_prefix_str(s: String) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.warn_str = s;
+/// Print a message with the warning prefix, at most once per `interval` per
+/// call site.
+/// 
+/// Useful in polling loops, where a warning on every iteration would flood
+/// the log:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::warn_interval;
+/// use std::time::Duration;
+/// 
+/// for i in 0..1000 {
+///     warn_interval!(Duration::from_secs(1), "Still waiting ({})", i);
+/// }
+/// // Prints: [!] Still waiting (0)
+/// ```
+#[macro_export]
+macro_rules! warn_interval {
+    ($interval:expr, $($arg:tt)+) => (
+        {
+            static LAST: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+            if $crate::_interval_elapsed(&LAST, $interval) {
+                $crate::_log($crate::Severity::Warn, format_args!($($arg)+));
+            }
+        }
+    )
 }
 
-/// Change the info prefix to a new static value.
-pub fn info_prefix_str(s: String) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.info_str = s;
+/// Print a message with the error prefix, only the first time `key` is
+/// seen. See [`warn_once_per!()`]
+/// 
+/// [`warn_once_per!()`]: macro.warn_once_per.html
+#[macro_export]
+macro_rules! err_once_per {
+    ($key:expr, $($arg:tt)+) => ({
+        $crate::_log_once_per($crate::Severity::Err, &$key, format_args!($($arg)+));
+    })
 }
 
-/// Change the debug prefix to a new static value.
-pub fn debug_prefix_str(s: String) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.debug_str = s;
+/// Print a message with the warning prefix, only the first time `key` is
+/// seen, regardless of the call site.
+/// 
+/// Keys may be of any type implementing `Hash` and are distinguished by
+/// type, so `1u32` and `1u64` are different keys. The seen keys are
+/// forgotten with [`clear_once_per_keys`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::warn_once_per;
+/// for code in &[404, 500, 404, 404] {
+///     warn_once_per!(*code, "Got status code {}", code);
+/// }
+/// // Prints:
+/// // [!] Got status code 404
+/// // [!] Got status code 500
+/// ```
+/// 
+/// [`clear_once_per_keys`]: fn.clear_once_per_keys.html
+#[macro_export]
+macro_rules! warn_once_per {
+    ($key:expr, $($arg:tt)+) => ({
+        $crate::_log_once_per($crate::Severity::Warn, &$key, format_args!($($arg)+));
+    })
 }
 
-/// Change the error prefix to a dynamic value.
-pub fn error_prefix_fn(f: PrefixFn) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.err_fn = Some(f);
+/// Print a message with the info prefix, only the first time `key` is
+/// seen. See [`warn_once_per!()`]
+/// 
+/// [`warn_once_per!()`]: macro.warn_once_per.html
+#[macro_export]
+macro_rules! info_once_per {
+    ($key:expr, $($arg:tt)+) => ({
+        $crate::_log_once_per($crate::Severity::Info, &$key, format_args!($($arg)+));
+    })
 }
 
-/// Change the warning prefix to a dynamic value.
-pub fn warning_prefix_fn(f: PrefixFn) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.warn_fn = Some(f);
+/// Print the lines which differ between two texts with the info prefix,
+/// each prefixed by `label`. Removed lines are marked with `-` and added
+/// lines with `+`; unchanged lines are left out.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_diff;
+/// let old = "port = 80\nhost = a\n";
+/// let new = "port = 8080\nhost = a\n";
+/// info_diff!("config", old, new);
+/// // Prints:
+/// // [*] config: - port = 80
+/// // [*] config: + port = 8080
+/// ```
+/// 
+/// The diff is a shortest one, so as few lines as possible are printed:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_diff;
+/// # #[cfg(feature = "test-utils")] {
+/// fn diff(old: &str, new: &str) -> Vec<String> {
+///     let mock = mhlog::MockLogger::install();
+///     info_diff!("d", old, new);
+///     mock.records().into_iter().map(|(_, msg)| msg).collect()
+/// }
+/// 
+/// assert_eq!(diff("", ""), Vec::<String>::new());
+/// assert_eq!(diff("", "x\ny\n"), ["d: + x", "d: + y"]);
+/// assert_eq!(diff("x\ny\n", ""), ["d: - x", "d: - y"]);
+/// assert_eq!(diff("a\nb\nc\n", "x\ny\n"), ["d: - a", "d: - b", "d: - c", "d: + x", "d: + y"]);
+/// // Odd and even difference in length.
+/// assert_eq!(diff("a\nb\nc\n", "a\nc\n"), ["d: - b"]);
+/// assert_eq!(diff("a\nb\nc\nd\n", "b\nd\n"), ["d: - a", "d: - c"]);
+/// assert_eq!(diff("a\nb\n", "x\na\ny\nb\nz\n"), ["d: + x", "d: + y", "d: + z"]);
+/// // Repeated lines.
+/// assert_eq!(diff("a\na\nb\na\n", "a\nb\na\na\n").len(), 2);
+/// assert_eq!(diff("a\nb\na\nb\na\n", "b\na\nb\na\nb\n"), ["d: - a", "d: + b"]);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! info_diff {
+    ($label:expr, $old:expr, $new:expr) => ({
+        $crate::_log_diff($crate::Severity::Info, $label, $old, $new);
+    })
 }
 
-/// Change the info prefix to a dynamic value.
-pub fn info_prefix_fn(f: PrefixFn) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.info_fn = Some(f);
+/// Print a key-value pair with the error prefix. See [`info_kv!()`]
+/// 
+/// [`info_kv!()`]: macro.info_kv.html
+#[macro_export]
+macro_rules! err_kv {
+    ($key:literal, $value:expr) => ({
+        $crate::err!("{}: {:?}", $key, $value);
+    });
+    ($key:expr, $value:expr) => ({
+        $crate::err!("{}: {:?}", stringify!($key), $value);
+    })
 }
 
-/// Change the debug prefix to a dynamic value.
-pub fn debug_prefix_fn(f: PrefixFn) {
-    let mut pre = PREFIXES.write().unwrap();
-    pre.debug_fn = Some(f);
+/// Print a key-value pair with the warning prefix. See [`info_kv!()`]
+/// 
+/// [`info_kv!()`]: macro.info_kv.html
+#[macro_export]
+macro_rules! warn_kv {
+    ($key:literal, $value:expr) => ({
+        $crate::warn!("{}: {:?}", $key, $value);
+    });
+    ($key:expr, $value:expr) => ({
+        $crate::warn!("{}: {:?}", stringify!($key), $value);
+    })
 }
 
-// -----------------------------------------------------------------------------
-// Severity
+/// Print a key-value pair with the info prefix, formatting the value with
+/// `{:?}`. An identifier or expression key is printed as written, a
+/// literal key as its value.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_kv;
+/// let user_id = 42;
+/// info_kv!(user_id, user_id); // Prints: [*] user_id: 42
+/// info_kv!("path", "/tmp");   // Prints: [*] path: "/tmp"
+/// ```
+#[macro_export]
+macro_rules! info_kv {
+    ($key:literal, $value:expr) => ({
+        $crate::info!("{}: {:?}", $key, $value);
+    });
+    ($key:expr, $value:expr) => ({
+        $crate::info!("{}: {:?}", stringify!($key), $value);
+    })
+}
 
-/// Log severity provides a common interface to all functionality which
-/// depends on the severity of a log message. Such as colouring, prefix,
-/// and stdout/stderr.
+/// Print a key-value pair with the info prefix, formatting the value with
+/// `{}`. See [`info_kv!()`]
 /// 
-#[doc(hidden)]
-pub enum Severity {
-    Err,
-    Warn,
-    Info,
-    Verbose,
-    Debug,
+/// [`info_kv!()`]: macro.info_kv.html
+#[macro_export]
+macro_rules! info_kv_display {
+    ($key:literal, $value:expr) => ({
+        $crate::info!("{}: {}", $key, $value);
+    });
+    ($key:expr, $value:expr) => ({
+        $crate::info!("{}: {}", stringify!($key), $value);
+    })
 }
 
-impl Severity {
-    #[cfg(feature = "colours")]
-    pub fn style(&self, txt: String) -> console::StyledObject<String> {
-        use console::style;
-        use Severity::*;
+/// Print a key-value pair with the debug prefix. See [`info_kv!()`]
+/// 
+/// [`info_kv!()`]: macro.info_kv.html
+#[macro_export]
+macro_rules! debug_kv {
+    ($key:literal, $value:expr) => ({
+        $crate::debug!("{}: {:?}", $key, $value);
+    });
+    ($key:expr, $value:expr) => ({
+        $crate::debug!("{}: {:?}", stringify!($key), $value);
+    })
+}
 
-        let obj = match self {
-            Err => style(txt).red(),
-            Warn => style(txt).yellow(),
-            Info|Verbose => style(txt),
-            Debug => style(txt).dim(),
-        };
+/// Print a deprecation notice, once per call site.
+/// 
+/// The notice is printed with the warning prefix, unless changed with
+/// [`set_deprecation_severity`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::warn_deprecated;
+/// fn old_api() {
+///     warn_deprecated!("old_api", "new_api");
+/// }
+/// old_api(); // Prints: [!] DEPRECATED: 'old_api' is deprecated; use 'new_api' instead
+/// old_api(); // Prints nothing
+/// ```
+/// 
+/// [`set_deprecation_severity`]: fn.set_deprecation_severity.html
+#[macro_export]
+macro_rules! warn_deprecated {
+    ($item:expr, $replacement:expr) => ({
+        static ONCE: std::sync::Once = std::sync::Once::new();
+        ONCE.call_once(|| $crate::_deprecated($item, $replacement));
+    })
+}
 
-        if self.to_stderr() {
-            obj.for_stderr()
-        } else {
-            obj.for_stdout()
-        }
-    }
+/// Print a hex dump of a byte slice with the debug prefix, if debug printing
+/// is enabled.
+/// 
+/// Each row shows the offset, the bytes in hex, and the printable ASCII
+/// characters (others are shown as `.`):
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::debug_hex;
+/// mhlog::set_debug(true);
+/// mhlog::set_hex_row_width(4);
+/// debug_hex!("label", b"Hello World");
+/// // Prints:
+/// // [~] label: 0000  48 65 6c 6c  Hell
+/// // [~]        0004  6f 20 57 6f  o Wo
+/// // [~]        0008  72 6c 64     rld
+/// ```
+/// 
+/// The number of bytes per row can be changed with [`set_hex_row_width`].
+/// 
+/// [`set_hex_row_width`]: fn.set_hex_row_width.html
+#[macro_export]
+macro_rules! debug_hex {
+    ($label:expr, $data:expr) => ({
+        $crate::_debug_hex($label, $data);
+    })
+}
 
-    pub fn to_stderr(&self) -> bool {
-        use Severity::*;
-        if cfg!(feature = "only_stderr") {
-            return true
-        }
-        if cfg!(feature = "only_stdout") {
-            return false
-        }
-        match self {
-            Err|Warn => true,
-            _ => false,
-        }
-    }
+/// Print the distribution of a slice of numbers as a sparkline with the
+/// debug prefix, if debug printing is enabled.
+/// 
+/// The values are counted in `buckets` equally wide buckets between the
+/// smallest and largest value, followed by the 50th, 95th and 99th
+/// percentiles:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::debug_histogram;
+/// mhlog::set_debug(true);
+/// let latency = [10.0, 12.0, 12.0, 13.0, 15.0, 20.0, 40.0, 87.0];
+/// debug_histogram!("latency", &latency, 7);
+/// // Prints:
+/// // [~] latency: █ ▂   ▂ (p50=13 p95=87 p99=87)
+/// ```
+#[macro_export]
+macro_rules! debug_histogram {
+    ($label:expr, $data:expr, $buckets:expr) => ({
+        $crate::_log_histogram($crate::Severity::Debug, $label, $data, $buckets);
+    })
+}
 
-    pub fn prefix(&self) -> String {
-        use Severity::*;
-        let pre = PREFIXES.read().unwrap();
+/// Print the distribution of a slice of numbers as a sparkline with the
+/// info prefix. See [`debug_histogram!()`]
+/// 
+/// [`debug_histogram!()`]: macro.debug_histogram.html
+#[macro_export]
+macro_rules! info_histogram {
+    ($label:expr, $data:expr, $buckets:expr) => ({
+        $crate::_log_histogram($crate::Severity::Info, $label, $data, $buckets);
+    })
+}
+
+/// Print a label with the time elapsed since the previous checkpoint on
+/// the current thread.
+/// 
+/// Printed with the debug prefix, unless changed with
+/// [`set_checkpoint_severity`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::checkpoint;
+/// mhlog::set_debug(true);
+/// checkpoint!("start");       // Prints: [~] start (first checkpoint)
+/// checkpoint!("step 1 done"); // Prints: [~] step 1 done (12.3µs since last checkpoint)
+/// ```
+/// 
+/// [`set_checkpoint_severity`]: fn.set_checkpoint_severity.html
+#[macro_export]
+macro_rules! checkpoint {
+    ($label:expr) => ({
+        $crate::_checkpoint($label);
+    })
+}
+
+/// Evaluate a block and print the time it took with the debug prefix, if
+/// debug printing is enabled.
+/// 
+/// Evaluates to the value of the block:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::timed;
+/// mhlog::set_debug(true);
+/// let sum = timed!("sum", {
+///     (0..1000).sum::<u64>()
+/// });
+/// // Prints: [~] sum: 1.23µs
+/// assert_eq!(sum, 499500);
+/// ```
+#[macro_export]
+macro_rules! timed {
+    ($label:expr, $body:block) => ({
+        let start = std::time::Instant::now();
+        let val = $body;
+        $crate::debug!("{}: {:?}", $label, start.elapsed());
+        val
+    })
+}
+
+/// Print a label with the time elapsed since `start` with the info prefix.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_elapsed;
+/// let start = std::time::Instant::now();
+/// info_elapsed!("Loading", start);
+/// // Prints: [*] Loading took 12.3µs
+/// ```
+/// 
+/// Use [`info_elapsed_human!()`] for long running tasks.
+/// 
+/// [`info_elapsed_human!()`]: macro.info_elapsed_human.html
+#[macro_export]
+macro_rules! info_elapsed {
+    ($label:expr, $start:expr) => ({
+        $crate::_log($crate::Severity::Info, format!("{} took {:?}", $label, $start.elapsed()));
+    })
+}
+
+/// Print a label with the time elapsed since `start` with the warning
+/// prefix. See [`info_elapsed!()`]
+/// 
+/// [`info_elapsed!()`]: macro.info_elapsed.html
+#[macro_export]
+macro_rules! warn_elapsed {
+    ($label:expr, $start:expr) => ({
+        $crate::_log($crate::Severity::Warn, format!("{} took {:?}", $label, $start.elapsed()));
+    })
+}
+
+/// Print a label with the time elapsed since `start` with the debug
+/// prefix. See [`info_elapsed!()`]
+/// 
+/// [`info_elapsed!()`]: macro.info_elapsed.html
+#[macro_export]
+macro_rules! debug_elapsed {
+    ($label:expr, $start:expr) => ({
+        $crate::_log($crate::Severity::Debug, format!("{} took {:?}", $label, $start.elapsed()));
+    })
+}
+
+/// Print a label with the time elapsed since `start` with the info prefix,
+/// formatted in hours, minutes and seconds.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_elapsed_human;
+/// let start = std::time::Instant::now();
+/// info_elapsed_human!("Backup", start);
+/// // After an hour, prints: [*] Backup took 1h 2m 3.4s
+/// ```
+#[macro_export]
+macro_rules! info_elapsed_human {
+    ($label:expr, $start:expr) => ({
+        let elapsed = $crate::_human_duration($start.elapsed());
+        $crate::_log($crate::Severity::Info, format!("{} took {}", $label, elapsed));
+    })
+}
+
+/// Call a closure, printing an ASCII box with the title before and after it
+/// with the info prefix.
+/// 
+/// The footer is printed also if the closure panics. Evaluates to the
+/// return value of the closure.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, info_block};
+/// info_block!("Phase 1: Loading", || {
+///     info!("Loading...");
+/// });
+/// // Prints:
+/// // [*] +--------------------------------------+
+/// // [*] | Phase 1: Loading                     |
+/// // [*] +--------------------------------------+
+/// // [*] Loading...
+/// // [*] +--------------------------------------+
+/// // [*] | Phase 1: Loading: done               |
+/// // [*] +--------------------------------------+
+/// ```
+#[macro_export]
+macro_rules! info_block {
+    ($title:expr, $f:expr) => ({
+        $crate::_info_block($title, $f)
+    })
+}
+
+/// Print a section divider with a centered title, with the info prefix.
+/// 
+/// The divider is 60 characters wide, unless changed with
+/// [`set_section_width`]. With the `colours` feature the divider is
+/// dimmed.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_section;
+/// info_section!("Phase 2");
+/// // Prints: [*] ═════════════════════════ Phase 2 ══════════════════════════
+/// ```
+/// 
+/// [`set_section_width`]: fn.set_section_width.html
+#[macro_export]
+macro_rules! info_section {
+    ($title:expr) => ({
+        $crate::_info_section($title);
+    })
+}
+
+/// Evaluate a block with a key-value pair pushed to the log context.
+/// 
+/// The pair is popped when the block exits, also on panic. Evaluates to
+/// the value of the block. See [`ctx_push`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{ctx_scope, info};
+/// let n = ctx_scope!("req_id", "abc123", {
+///     info!("Handling request");
+///     42
+/// });
+/// ```
+/// 
+/// [`ctx_push`]: fn.ctx_push.html
+#[macro_export]
+macro_rules! ctx_scope {
+    ($key:expr, $value:expr, $body:block) => ({
+        let _guard = $crate::_ctx_guard($key, $value);
+        $body
+    })
+}
+
+/// Pretty-print a value with the error prefix, using `{:#?}`.
+/// See [`err!()`]
+/// 
+/// [`err!()`]: macro.err.html
+#[macro_export]
+macro_rules! err_pretty {
+    ($e:expr) => (
+        $crate::err!("{:#?}", $e);
+    )
+}
+
+/// Pretty-print a value with the warning prefix, using `{:#?}`.
+/// See [`warn!()`]
+/// 
+/// [`warn!()`]: macro.warn.html
+#[macro_export]
+macro_rules! warn_pretty {
+    ($e:expr) => (
+        $crate::warn!("{:#?}", $e);
+    )
+}
+
+/// Pretty-print a value with the info prefix, using `{:#?}`.
+/// See [`info!()`]
+/// 
+/// [`info!()`]: macro.info.html
+#[macro_export]
+macro_rules! info_pretty {
+    ($e:expr) => ({
+        $crate::info!("{:#?}", $e);
+    })
+}
+
+/// Pretty-print a value with the debug prefix, using `{:#?}`.
+/// See [`debug!()`]
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::debug_pretty;
+/// mhlog::set_debug(true);
+/// debug_pretty!(vec![(1, "one"), (2, "two")]);
+/// ```
+/// 
+/// [`debug!()`]: macro.debug.html
+#[macro_export]
+macro_rules! debug_pretty {
+    ($e:expr) => ({
+        $crate::debug!("{:#?}", $e);
+    })
+}
+
+/// Pretty-print a value as JSON with the warning prefix. Requires the `json`
+/// feature. See [`info_pretty_json!()`]
+/// 
+/// [`info_pretty_json!()`]: macro.info_pretty_json.html
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! warn_pretty_json {
+    ($e:expr) => (
+        $crate::_pretty_json($crate::Severity::Warn, &$e);
+    )
+}
+
+/// Pretty-print a value as JSON with the info prefix. Requires the `json`
+/// feature.
+/// 
+/// The value must implement `serde::Serialize` and `Debug`. Each line of
+/// the JSON is printed as a separate message. If serialization fails the
+/// value is printed using `{:?}` instead, after a warning.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "json")] {
+/// # use mhlog::info_pretty_json;
+/// use std::collections::BTreeMap;
+/// 
+/// let mut config = BTreeMap::new();
+/// config.insert("threads", 4);
+/// info_pretty_json!(config);
+/// // Prints:
+/// // [*] {
+/// // [*]   "threads": 4
+/// // [*] }
+/// # }
+/// ```
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! info_pretty_json {
+    ($e:expr) => ({
+        $crate::_pretty_json($crate::Severity::Info, &$e);
+    })
+}
+
+/// Pretty-print a value as JSON with the debug prefix, if debug printing is
+/// enabled. Requires the `json` feature. See [`info_pretty_json!()`]
+/// 
+/// [`info_pretty_json!()`]: macro.info_pretty_json.html
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! debug_pretty_json {
+    ($e:expr) => ({
+        $crate::_pretty_json($crate::Severity::Debug, &$e);
+    })
+}
+
+/// Print a value as compact, single-line JSON with the warning prefix.
+/// See [`info_json!()`]
+/// 
+/// [`info_json!()`]: macro.info_json.html
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! warn_json {
+    ($e:expr) => ({
+        $crate::_json($crate::Severity::Warn, &$e);
+    })
+}
+
+/// Print a value as compact, single-line JSON with the info prefix.
+/// Requires the `json` feature.
+/// 
+/// The value must implement `serde::Serialize` and `Debug`. If
+/// serialization fails `<serialization failed>` is printed, followed by the
+/// value printed using `{:?}`.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "json")] {
+/// # use mhlog::info_json;
+/// use std::collections::BTreeMap;
+/// 
+/// let mut body = BTreeMap::new();
+/// body.insert("status", "ok");
+/// info_json!(body); // Prints: [*] {"status":"ok"}
+/// # }
+/// ```
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! info_json {
+    ($e:expr) => ({
+        $crate::_json($crate::Severity::Info, &$e);
+    })
+}
+
+/// Print a value as compact, single-line JSON with the debug prefix, if debug printing
+/// is enabled.
+/// See [`info_json!()`]
+/// 
+/// [`info_json!()`]: macro.info_json.html
+#[cfg(feature = "json")]
+#[macro_export]
+macro_rules! debug_json {
+    ($e:expr) => ({
+        $crate::_json($crate::Severity::Debug, &$e);
+    })
+}
+
+/// Print a message with the error prefix, followed by structured fields.
+/// 
+/// The fields are given as a `&[(&str, &str)]` slice, and are appended
+/// to the message as `key=val`. See [`err!()`]
+/// 
+/// [`err!()`]: macro.err.html
+#[macro_export]
+macro_rules! err_structured {
+    ($fields:expr, $($arg:tt)+) => (
+        $crate::_log_fields($crate::Severity::Err, format_args!($($arg)+), $fields);
+    )
+}
+
+/// Print a message with the warning prefix, followed by structured fields.
+/// See [`err_structured!()`]
+/// 
+/// [`err_structured!()`]: macro.err_structured.html
+#[macro_export]
+macro_rules! warn_structured {
+    ($fields:expr, $($arg:tt)+) => (
+        $crate::_log_fields($crate::Severity::Warn, format_args!($($arg)+), $fields);
+    )
+}
+
+/// Print a message with the info prefix, followed by structured fields.
+/// See [`err_structured!()`]
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info_structured;
+/// let user = "bob";
+/// info_structured!(&[("user", user), ("id", "42")], "Logged in");
+/// // Prints: [*] Logged in user=bob id=42
+/// ```
+/// 
+/// [`err_structured!()`]: macro.err_structured.html
+#[macro_export]
+macro_rules! info_structured {
+    ($fields:expr, $($arg:tt)+) => ({
+        $crate::_log_fields($crate::Severity::Info, format_args!($($arg)+), $fields);
+    })
+}
+
+/// Print a message with the info prefix, followed by structured fields,
+/// if verbose printing is enabled. See [`err_structured!()`]
+/// 
+/// [`err_structured!()`]: macro.err_structured.html
+#[macro_export]
+macro_rules! verbose_structured {
+    ($fields:expr, $($arg:tt)+) => ({
+        $crate::_log_fields($crate::Severity::Verbose, format_args!($($arg)+), $fields);
+    })
+}
+
+/// Print a message with the debug prefix, followed by structured fields,
+/// if debug printing is enabled. See [`err_structured!()`]
+/// 
+/// [`err_structured!()`]: macro.err_structured.html
+#[macro_export]
+macro_rules! debug_structured {
+    ($fields:expr, $($arg:tt)+) => ({
+        $crate::_log_fields($crate::Severity::Debug, format_args!($($arg)+), $fields);
+    })
+}
+
+/// Print a message with the error prefix and a numeric error code.
+/// 
+/// The code is formatted as `E{code:04}`. If the code is found in the
+/// registry set with [`set_error_code_registry`], its name is appended:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err_code;
+/// use std::collections::HashMap;
+/// 
+/// err_code!(7, "Could not open {}", "foo.txt");
+/// // Prints: [!!] E0007: Could not open foo.txt
+/// 
+/// let mut registry = HashMap::new();
+/// registry.insert(7, "FILE_NOT_FOUND");
+/// mhlog::set_error_code_registry(registry);
+/// err_code!(7, "Could not open {}", "foo.txt");
+/// // Prints: [!!] E0007: Could not open foo.txt (FILE_NOT_FOUND)
+/// ```
+/// 
+/// [`set_error_code_registry`]: fn.set_error_code_registry.html
+#[macro_export]
+macro_rules! err_code {
+    ($code:expr, $($arg:tt)+) => (
+        $crate::_err_code($code, format_args!($($arg)+));
+    )
+}
+
+/// Print an error and its chain of sources with the error prefix.
+/// 
+/// Each source is printed as a separate message, with increasing
+/// indentation:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err_chain;
+/// # let e = std::io::Error::new(std::io::ErrorKind::Other, "outer");
+/// err_chain!(e);
+/// // For an error with two levels of sources, prints:
+/// // [!!] outer
+/// // [!!]   caused by: middle
+/// // [!!]     caused by: inner
+/// ```
+#[macro_export]
+macro_rules! err_chain {
+    ($e:expr) => (
+        $crate::_log_chain($crate::Severity::Err, &$e);
+    )
+}
+
+/// Print a message with the error prefix and an I/O error kind, and count
+/// the error kind.
+/// 
+/// The counts are available with [`io_error_count`], for use in retry
+/// logic and the like. Errors are counted even if error messages are
+/// suppressed.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err_io;
+/// use std::io::ErrorKind;
+/// 
+/// err_io!(ErrorKind::TimedOut, "Connecting to {}", "db");
+/// // Prints: [!!] [TimedOut] Connecting to db
+/// assert_eq!(mhlog::io_error_count(ErrorKind::TimedOut), 1);
+/// ```
+/// 
+/// [`io_error_count`]: fn.io_error_count.html
+#[macro_export]
+macro_rules! err_io {
+    ($kind:expr, $($arg:tt)+) => ({
+        $crate::_err_io($kind, format_args!($($arg)+));
+    })
+}
+
+/// Print an error and its chain of sources with the warning prefix.
+/// See [`err_chain!()`]
+/// 
+/// [`err_chain!()`]: macro.err_chain.html
+#[macro_export]
+macro_rules! warn_chain {
+    ($e:expr) => (
+        $crate::_log_chain($crate::Severity::Warn, &$e);
+    )
+}
+
+/// Print an error and its chain of sources with the info prefix.
+/// See [`err_chain!()`]
+/// 
+/// [`err_chain!()`]: macro.err_chain.html
+#[macro_export]
+macro_rules! info_chain {
+    ($e:expr) => ({
+        $crate::_log_chain($crate::Severity::Info, &$e);
+    })
+}
+
+/// Print the error of a `Result` with the error prefix and `context`, and
+/// pass the `Result` through unchanged.
+/// 
+/// `result` is evaluated exactly once, making this a shorter form of
+/// `result.map_err(|e| { err!("{}: {}", context, e); e })`:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err_map;
+/// fn read_config() -> std::io::Result<String> {
+///     let s = err_map!(std::fs::read_to_string("/no/such/file"), "Reading config")?;
+///     // Prints: [!!] Reading config: No such file or directory (os error 2)
+///     Ok(s)
+/// }
+/// assert!(read_config().is_err());
+/// 
+/// let n: Result<u32, String> = err_map!(Ok(42), "Parsing");
+/// assert_eq!(n, Ok(42));
+/// ```
+#[macro_export]
+macro_rules! err_map {
+    ($result:expr, $ctx:expr) => (
+        match $result {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                $crate::_log($crate::Severity::Err, format_args!("{}: {}", $ctx, e));
+                Err(e)
+            }
+        }
+    )
+}
+
+/// Print the error of a `Result` with the warning prefix and `context`.
+/// See [`err_map!()`]
+/// 
+/// [`err_map!()`]: macro.err_map.html
+#[macro_export]
+macro_rules! warn_map {
+    ($result:expr, $ctx:expr) => (
+        match $result {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                $crate::_log($crate::Severity::Warn, format_args!("{}: {}", $ctx, e));
+                Err(e)
+            }
+        }
+    )
+}
+
+/// Print the error of a `Result` with the info prefix and `context`.
+/// See [`err_map!()`]
+/// 
+/// [`err_map!()`]: macro.err_map.html
+#[macro_export]
+macro_rules! info_map {
+    ($result:expr, $ctx:expr) => (
+        match $result {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                $crate::_log($crate::Severity::Info, format_args!("{}: {}", $ctx, e));
+                Err(e)
+            }
+        }
+    )
+}
+
+/// Print a message with the error prefix and exit with error code 1.
+/// See [`err!()`]
+/// 
+/// The exit code can be changed with [`set_err_exit_code`], and cleanup
+/// may be done before exiting with [`set_fatal_exit_fn`].
+/// 
+/// [`err!()`]: macro.err.html
+/// [`set_err_exit_code`]: fn.set_err_exit_code.html
+/// [`set_fatal_exit_fn`]: fn.set_fatal_exit_fn.html
+#[macro_export]
+macro_rules! bail {
+    ($($arg:tt)+) => ({
+        $crate::err!($($arg)+);
+        $crate::_exit($crate::_err_exit_code());
+    });
+}
+
+/// Print a message with the error prefix and return it as a [`BailError`].
+/// 
+/// Unlike [`bail!()`] the process is not exited, so the error may be
+/// handled by the caller and destructors run as usual. The error is
+/// converted with `Into`, so any error type `BailError` converts into may
+/// be returned, like `Box<dyn Error>`.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{bail_result, BailError};
+/// fn parse(arg: &str) -> Result<u32, BailError> {
+///     match arg.parse() {
+///         Ok(n) => Ok(n),
+///         Err(_) => bail_result!("Invalid number: {}", arg),
+///     }
+/// }
+/// assert!(parse("x").is_err());
+/// ```
+/// 
+/// [`BailError`]: struct.BailError.html
+/// [`bail!()`]: macro.bail.html
+#[macro_export]
+macro_rules! bail_result {
+    ($($arg:tt)+) => ({
+        let msg = format!($($arg)+);
+        $crate::_log($crate::Severity::Err, msg.clone());
+        return Err($crate::BailError::new(msg).into());
+    });
+}
+
+/// Return an [`EnsureError`] from the current function if a condition is
+/// false, after printing the message with the error prefix. In the style
+/// of `anyhow::ensure!`.
+/// 
+/// The error is converted with `Into`, like with [`bail_result!()`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{ensure, EnsureError};
+/// fn check_port(port: u32) -> Result<u16, EnsureError> {
+///     ensure!(port <= 65535, "Port {} is out of range", port);
+///     Ok(port as u16)
+/// }
+/// assert!(check_port(8080).is_ok());
+/// assert!(check_port(70000).is_err());
+/// ```
+/// 
+/// [`EnsureError`]: struct.EnsureError.html
+/// [`bail_result!()`]: macro.bail_result.html
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => ({
+        if !$cond {
+            let msg = format!($($arg)+);
+            $crate::_log($crate::Severity::Err, msg.clone());
+            return Err($crate::EnsureError::new(msg).into());
+        }
+    });
+}
+
+/// Print a message with the error prefix and exit with the given exit code.
+/// 
+/// Useful for the common CLI pattern of exiting with a specific code on
+/// e.g. usage errors:
+/// 
+/// ```rust,no_run
+/// # extern crate mhlog;
+/// # use mhlog::err_exit;
+/// err_exit!(2, "Too many arguments: {}", 3);
+/// ```
+/// 
+/// See [`bail!()`] for exiting with exit code 1.
+/// 
+/// [`bail!()`]: macro.bail.html
+#[macro_export]
+macro_rules! err_exit {
+    ($code:expr, $($arg:tt)+) => ({
+        $crate::_log($crate::Severity::Err, format_args!($($arg)+));
+        $crate::_exit($code);
+    });
+}
+
+#[doc(hidden)]
+pub fn _log<'a, M: Into<LogMsg<'a>>>(severity: Severity, msg: M) {
+    _log_fields(severity, msg, &[]);
+}
+
+#[doc(hidden)]
+pub fn _log_fields<'a, M: Into<LogMsg<'a>>>(severity: Severity, msg: M, fields: &[(&str, &str)]) {
+    log_inner(severity, msg.into(), fields, None);
+}
+
+#[doc(hidden)]
+pub fn _log_target(severity: Severity, target: &str, args: std::fmt::Arguments) {
+    let logger = TARGETS.read().unwrap().get(target).cloned();
+    match logger {
+        Some(logger) => logger.log(severity, args),
+        None => log_inner(severity, args.into(), &[], Some(target)),
+    }
+}
+
+/// The common implementation of `_log_fields` and `_log_target`. The
+/// target, if any, is added to the prefix.
+fn log_inner(severity: Severity, msg: LogMsg, fields: &[(&str, &str)], target: Option<&str>) {
+    let severity = severity.redirected();
+    if severity.suppressed() {
+        return
+    }
+
+    let skipped = match sample(severity) {
+        Some(n) => n,
+        None => return,
+    };
+
+    let mut msg = msg.into_str();
+    if let Some(f) = &*MESSAGE_TRANSFORM.read().unwrap() {
+        msg = f(severity, msg.into_owned()).into();
+    }
+    if skipped > 0 {
+        msg = format!("{} (skipped {} similar messages)", msg, skipped).into();
+    }
+    {
+        let interceptors = INTERCEPTORS.read().unwrap();
+        if !interceptors.is_empty() {
+            let mut s = msg.into_owned();
+            if !interceptors.iter().all(|i| i.intercept(severity, &mut s)) {
+                return
+            }
+            msg = s.into();
+        }
+    }
+    #[cfg(feature = "metrics")]
+    {
+        // Start the time of the rates at the first message counted.
+        lazy_static::initialize(&COUNTS_RESET);
+        MESSAGE_COUNTS[severity.index()].fetch_add(1, Ordering::Relaxed);
+    }
+    #[cfg(feature = "test-utils")]
+    {
+        CAPTURE_COUNTS[severity.index()].fetch_add(1, Ordering::SeqCst);
+        THREAD_COUNTS.with(|counts| {
+            let mut c = counts.get();
+            c[severity.index()] += 1;
+            counts.set(c);
+        });
+        let mocks = MOCKS.read().unwrap();
+        if !mocks.is_empty() {
+            let record = LogRecord::builder()
+                .severity(severity)
+                .message(msg.into_owned())
+                .build();
+            for mock in mocks.iter() {
+                mock.records.lock().unwrap().push(record.clone());
+            }
+            return
+        }
+    }
+
+    let prefix = if *COMPACT.read().unwrap() {
+        String::new()
+    } else {
+        let mut prefix = severity.prefix();
+        if let Some(target) = target {
+            let (open, close) = PREFIXES.read().unwrap().bracket;
+            prefix = format!("{}{}{}{}", prefix, open, target, close);
+        }
+        prefix
+    };
+    let indent = INDENT.with(|i| i.get()) + GROUP_DEPTH.with(|d| d.get());
+    if indent > 0 {
+        msg = format!("{:width$}{}", "", msg, width = indent * 2).into();
+    }
+    if MESSAGE_IDS.load(Ordering::Relaxed) {
+        let id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::SeqCst);
+        LAST_MESSAGE_ID.store(id, Ordering::SeqCst);
+        msg = format!("{:08x} {}", id, msg).into();
+    }
+    #[cfg(feature = "udp-syslog")]
+    syslog_send(severity, &msg);
+    let txt = format_line(severity, &prefix, &msg, fields);
+    write_out(severity.to_stderr(), &encode(&txt));
+}
+
+/// Build a line with only the prefix and message, as for a [`Logger`],
+/// without the global formatter, padding, fields and colours.
+/// 
+/// [`Logger`]: struct.Logger.html
+fn format_plain(prefix: &str, msg: &str) -> String {
+    let mut txt = DefaultFormatter.format(Severity::Info, prefix, msg);
+    txt.push('\n');
+    txt
+}
+
+/// Build a complete log line, including the trailing newline and colouring.
+/// The prefix and message are combined by the formatter set with
+/// [`set_formatter`](fn.set_formatter.html).
+fn format_line(severity: Severity, prefix: &str, msg: &str, fields: &[(&str, &str)]) -> String {
+    use std::fmt::Write;
+
+    let prefix = &pad_prefix(prefix);
+    let mut txt = match &*FORMATTER.read().unwrap() {
+        Some(f) => f.format(severity, prefix, msg),
+        None => DefaultFormatter.format(severity, prefix, msg),
+    };
+    for (key, val) in fields {
+        let _ = write!(txt, " {}={}", key, val);
+    }
+    {
+        let global = GLOBAL_FIELDS.read().unwrap();
+        if !global.is_empty() {
+            let mut global: Vec<_> = global.iter()
+                .filter(|(key, _)| !fields.iter().any(|(k, _)| k == key))
+                .collect();
+            global.sort();
+            for (key, val) in global {
+                let _ = write!(txt, " {}={}", key, val);
+            }
+        }
+    }
+    CTX.with(|ctx| {
+        for (key, val) in &ctx.borrow().fields {
+            let _ = write!(txt, " {}={}", key, val);
+        }
+    });
+    #[cfg(all(feature = "simple-colors", not(feature = "colours")))]
+    {
+        if let Some(code) = ANSI_COLORS.read().unwrap().get(&severity) {
+            txt = format!("{}{}\x1b[0m", code, txt);
+        }
+    }
+    #[cfg(feature = "colours")]
+    {
+        let ext = EXT_COLORS.read().unwrap().get(&severity).copied();
+        if let Some(color) = ext {
+            if severity.colors_enabled() {
+                txt = format!("{}{}\x1b[0m", color.escape(), txt);
+            }
+            txt.push('\n');
+            return txt
+        }
+    }
+    txt.push('\n');
+    #[cfg(feature = "colours")]
+    let txt = severity.style(txt).to_string();
+    #[cfg(not(any(feature = "colours", feature = "simple-colors")))]
+    let _ = severity;
+    txt
+}
+
+/// Check whether `interval` has elapsed since the time stored in `last`,
+/// and if so update it. `last` holds nanoseconds since `EPOCH` plus one,
+/// or zero if never set.
+#[doc(hidden)]
+pub fn _interval_elapsed(last: &AtomicU64, interval: Duration) -> bool {
+    let now = EPOCH.elapsed().as_nanos() as u64 + 1;
+    let prev = last.load(Ordering::Relaxed);
+    if prev != 0 && now.saturating_sub(prev) < interval.as_nanos() as u64 {
+        return false
+    }
+    last.compare_exchange(prev, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+}
+
+#[doc(hidden)]
+pub fn _log_once_per<K: std::hash::Hash + 'static>(severity: Severity, key: &K, args: std::fmt::Arguments) {
+    use std::hash::Hasher;
+
+    if severity.redirected().suppressed() {
+        return
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    let key = (std::any::TypeId::of::<K>(), hasher.finish());
+    if ONCE_PER_KEYS.write().unwrap().insert(key) {
+        _log(severity, args);
+    }
+}
+
+/// Forget the keys seen by [`warn_once_per!()`] and friends, so their
+/// messages are printed again.
+/// 
+/// [`warn_once_per!()`]: macro.warn_once_per.html
+pub fn clear_once_per_keys() {
+    ONCE_PER_KEYS.write().unwrap().clear();
+}
+
+#[doc(hidden)]
+pub fn _log_diff(severity: Severity, label: &str, old: &str, new: &str) {
+    if severity.redirected().suppressed() {
+        return
+    }
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let mut edits = Vec::new();
+    diff_lines(&a, &b, &mut edits);
+
+    // Each run of changes between unchanged lines is printed as all its
+    // removals followed by all its additions.
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for edit in edits.into_iter().map(Some).chain(std::iter::once(None)) {
+        match edit {
+            Some(Edit::Remove(line)) => removed.push(line),
+            Some(Edit::Add(line)) => added.push(line),
+            Some(Edit::Keep) | None => {
+                for line in removed.drain(..) {
+                    _log(severity, format!("{}: - {}", label, line));
+                }
+                for line in added.drain(..) {
+                    _log(severity, format!("{}: + {}", label, line));
+                }
+            }
+        }
+    }
+}
+
+/// A step of a line diff.
+enum Edit<'a> {
+    Keep,
+    Remove(&'a str),
+    Add(&'a str),
+}
+
+/// Push the edits turning `a` into `b` to `edits`, with the linear space
+/// variant of Myers' diff algorithm: find the middle snake of a shortest
+/// edit script, and recurse on the parts before and after it.
+fn diff_lines<'a>(a: &[&'a str], b: &[&'a str], edits: &mut Vec<Edit<'a>>) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+    edits.extend((0..prefix).map(|_| Edit::Keep));
+
+    if a.is_empty() {
+        edits.extend(b.iter().map(|line| Edit::Add(line)));
+    } else if b.is_empty() {
+        edits.extend(a.iter().map(|line| Edit::Remove(line)));
+    } else {
+        // With a common prefix and suffix removed from non-empty sequences,
+        // at least two edits are needed, so both parts are smaller.
+        let (x, y, u, v) = middle_snake(a, b);
+        diff_lines(&a[..x], &b[..y], edits);
+        edits.extend((x..u).map(|_| Edit::Keep));
+        diff_lines(&a[u..], &b[v..], edits);
+    }
+    edits.extend((0..suffix).map(|_| Edit::Keep));
+}
+
+/// The middle snake of a shortest edit script turning `a` into `b`, from
+/// `(x, y)` to `(u, v)`, searching forwards and backwards at once.
+fn middle_snake(a: &[&str], b: &[&str]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // Furthest x reached on each diagonal k = x - y, forwards and
+    // backwards. Backwards, x and y count from the ends.
+    let mut vf = vec![0isize; 2 * offset as usize + 1];
+    let mut vb = vec![0isize; 2 * offset as usize + 1];
+    let at = |k: isize| (k + offset) as usize;
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let x0 = if k == -d || (k != d && vf[at(k - 1)] < vf[at(k + 1)]) {
+                vf[at(k + 1)]
+            } else {
+                vf[at(k - 1)] + 1
+            };
+            let (mut x, mut y) = (x0, x0 - k);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            vf[at(k)] = x;
+            if delta % 2 != 0 && (delta - k).abs() < d && x + vb[at(delta - k)] >= n {
+                return (x0 as usize, (x0 - k) as usize, x as usize, y as usize)
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let x0 = if k == -d || (k != d && vb[at(k - 1)] < vb[at(k + 1)]) {
+                vb[at(k + 1)]
+            } else {
+                vb[at(k - 1)] + 1
+            };
+            let (mut x, mut y) = (x0, x0 - k);
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            vb[at(k)] = x;
+            if delta % 2 == 0 && (delta - k).abs() <= d && x + vf[at(delta - k)] >= n {
+                return ((n - x) as usize, (m - y) as usize, (n - x0) as usize, (m - x0 + k) as usize)
+            }
+        }
+    }
+    unreachable!("no middle snake found")
+}
+
+/// Run the fatal exit function, flush the log output, and exit.
+#[doc(hidden)]
+pub fn _exit(code: i32) -> ! {
+    let f = *FATAL_EXIT_FN.read().unwrap();
+    if let Some(f) = f {
+        if std::panic::catch_unwind(f).is_err() {
+            std::process::abort();
+        }
+    }
+    write_barrier();
+    std::process::exit(code)
+}
+
+/// Set a function which is called by [`bail!()`] and [`err_exit!()`]
+/// before exiting, for cleanup like releasing file locks. Replaces any
+/// previous function.
+/// 
+/// The function should complete quickly. If it panics the process is
+/// aborted.
+/// 
+/// [`bail!()`]: macro.bail.html
+/// [`err_exit!()`]: macro.err_exit.html
+pub fn set_fatal_exit_fn(f: fn()) {
+    let mut exit_fn = FATAL_EXIT_FN.write().unwrap();
+    *exit_fn = Some(f);
+}
+
+#[doc(hidden)]
+pub fn _err_exit_code() -> i32 {
+    ERR_EXIT_CODE.load(Ordering::Relaxed)
+}
+
+/// Change the exit code used by [`bail!()`] from the default of 1.
+/// 
+/// Only `bail!()` is affected; [`err_exit!()`] always exits with the code
+/// it is given.
+/// 
+/// [`bail!()`]: macro.bail.html
+/// [`err_exit!()`]: macro.err_exit.html
+pub fn set_err_exit_code(code: i32) {
+    ERR_EXIT_CODE.store(code, Ordering::Relaxed);
+}
+
+#[doc(hidden)]
+pub fn _deprecated(item: &str, replacement: &str) {
+    let severity = *DEPRECATION_SEVERITY.read().unwrap();
+    _log(severity, format!("DEPRECATED: '{}' is deprecated; use '{}' instead", item, replacement));
+}
+
+#[cfg(feature = "json")]
+#[doc(hidden)]
+pub fn _json<T: serde::Serialize + std::fmt::Debug + ?Sized>(severity: Severity, val: &T) {
+    if severity.redirected().suppressed() {
+        return
+    }
+    match serde_json::to_string(val) {
+        Ok(json) => _log(severity, json),
+        Err(_) => _log(severity, format!("<serialization failed> {:?}", val)),
+    }
+}
+
+#[cfg(feature = "json")]
+#[doc(hidden)]
+pub fn _pretty_json<T: serde::Serialize + std::fmt::Debug + ?Sized>(severity: Severity, val: &T) {
+    if severity.redirected().suppressed() {
+        return
+    }
+    match serde_json::to_string_pretty(val) {
+        Ok(json) => {
+            for line in json.lines() {
+                _log(severity, line.to_string());
+            }
+        }
+        Err(e) => {
+            _log(Severity::Warn, format!("Failed to serialize value as JSON: {}", e));
+            _log(severity, format!("{:?}", val));
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _log_chain(severity: Severity, e: &dyn std::error::Error) {
+    _log(severity, e.to_string());
+    let mut source = e.source();
+    let mut depth = 1;
+    while let Some(e) = source {
+        _log(severity, format!("{}caused by: {}", "  ".repeat(depth), e));
+        source = e.source();
+        depth += 1;
+    }
+}
+
+/// Log an error and its sources on one line, separated by `: `.
+#[doc(hidden)]
+pub fn _log_error(severity: Severity, e: &dyn std::error::Error) {
+    if severity.redirected().suppressed() {
+        return
+    }
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        msg.push_str(": ");
+        msg.push_str(&e.to_string());
+        source = e.source();
+    }
+    _log(severity, msg);
+}
+
+#[doc(hidden)]
+pub fn _err_io(kind: std::io::ErrorKind, args: std::fmt::Arguments) {
+    let counted = IO_ERROR_COUNTS.read().unwrap()
+        .get(&kind)
+        .map(|n| n.fetch_add(1, Ordering::Relaxed))
+        .is_some();
+    if !counted {
+        IO_ERROR_COUNTS.write().unwrap()
+            .entry(kind)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    if Severity::Err.redirected().suppressed() {
+        return
+    }
+    _log(Severity::Err, format!("[{:?}] {}", kind, args));
+}
+
+/// The number of errors of an I/O error kind logged with [`err_io!()`].
+/// 
+/// [`err_io!()`]: macro.err_io.html
+pub fn io_error_count(kind: std::io::ErrorKind) -> u64 {
+    IO_ERROR_COUNTS.read().unwrap()
+        .get(&kind)
+        .map_or(0, |n| n.load(Ordering::Relaxed))
+}
+
+#[doc(hidden)]
+pub fn _err_code(code: u32, args: std::fmt::Arguments) {
+    let msg = match ERROR_CODES.read().unwrap().get(&code) {
+        Some(name) => format!("E{:04}: {} ({})", code, args, name),
+        None => format!("E{:04}: {}", code, args),
+    };
+    _log(Severity::Err, msg);
+}
+
+#[doc(hidden)]
+pub fn _info_block<T, F: FnOnce() -> T>(title: &str, f: F) -> T {
+    struct Footer(String, usize);
+
+    impl Drop for Footer {
+        fn drop(&mut self) {
+            info_box(&self.0, self.1);
+        }
+    }
+
+    let footer = format!("{}: done", title);
+    let width = std::cmp::max(40, footer.chars().count() + 4);
+    info_box(title, width);
+    let _footer = Footer(footer, width);
+    f()
+}
+
+#[doc(hidden)]
+pub fn _info_section(title: &str) {
+    let width = SECTION_WIDTH.load(Ordering::Relaxed);
+    let fill = width.saturating_sub(display_width(title) + 2).max(2);
+    let (left, right) = ("═".repeat(fill / 2), "═".repeat(fill - fill / 2));
+    #[cfg(feature = "colours")]
+    let (left, right) = if Severity::Info.colors_enabled() {
+        (format!("\x1b[2m{}\x1b[0m", left), format!("\x1b[2m{}\x1b[0m", right))
+    } else {
+        (left, right)
+    };
+    _log(Severity::Info, format!("{} {} {}", left, title, right));
+}
+
+/// Print a title in an ASCII box, `width` characters wide.
+fn info_box(title: &str, width: usize) {
+    let border = format!("+{}+", "-".repeat(width - 2));
+    _log(Severity::Info, border.clone());
+    _log(Severity::Info, format!("| {:<w$} |", title, w = width - 4));
+    _log(Severity::Info, border);
+}
+
+#[doc(hidden)]
+pub fn _checkpoint(label: &str) {
+    let now = std::time::Instant::now();
+    let msg = match LAST_CHECKPOINT.with(|last| last.replace(Some(now))) {
+        Some(last) => format!("{} ({:?} since last checkpoint)", label, now - last),
+        None => format!("{} (first checkpoint)", label),
+    };
+    let severity = *CHECKPOINT_SEVERITY.read().unwrap();
+    _log(severity, msg);
+}
+
+#[doc(hidden)]
+pub fn _human_duration(d: Duration) -> String {
+    let tenths = (d.as_secs_f64() * 10.0).round() as u64;
+    let (h, m, s) = (tenths / 36000, tenths / 600 % 60, tenths % 600);
+    let secs = format!("{}.{}s", s / 10, s % 10);
+    match (h, m) {
+        (0, 0) => secs,
+        (0, m) => format!("{}m {}", m, secs),
+        (h, m) => format!("{}h {}m {}", h, m, secs),
+    }
+}
+
+#[doc(hidden)]
+pub fn _debug_hex(label: &str, data: &[u8]) {
+    use std::fmt::Write;
+
+    let severity = Severity::Debug;
+    if severity.redirected().suppressed() {
+        return
+    }
+
+    let width = HEX_ROW_WIDTH.load(Ordering::Relaxed);
+    let indent = " ".repeat(label.chars().count() + 2);
+    for (i, row) in data.chunks(width).enumerate() {
+        let mut line = if i == 0 {
+            format!("{}: ", label)
+        } else {
+            indent.clone()
+        };
+        let _ = write!(line, "{:04x} ", i * width);
+        for b in row {
+            let _ = write!(line, " {:02x}", b);
+        }
+        line.push_str(&"   ".repeat(width - row.len()));
+        line.push_str("  ");
+        line.extend(row.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }
+        }));
+        _log(severity, line);
+    }
+}
+
+#[doc(hidden)]
+pub fn _log_histogram(severity: Severity, label: &str, data: &[f64], buckets: usize) {
+    const BARS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    if severity.redirected().suppressed() {
+        return
+    }
+
+    let mut sorted: Vec<f64> = data.iter().copied().filter(|x| !x.is_nan()).collect();
+    if sorted.is_empty() {
+        _log(severity, format!("{}: (no data)", label));
+        return
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let buckets = buckets.max(1);
+    let (min, max) = (sorted[0], sorted[sorted.len() - 1]);
+    let mut counts = vec![0usize; buckets];
+    for x in &sorted {
+        let i = if max > min {
+            ((x - min) / (max - min) * buckets as f64) as usize
+        } else {
+            0
+        };
+        counts[i.min(buckets - 1)] += 1;
+    }
+    let most = *counts.iter().max().unwrap();
+    let spark: String = counts.iter()
+        .map(|&n| BARS[(n * 8).div_ceil(most)])
+        .collect();
+
+    // Nearest-rank percentile.
+    let pct = |p: usize| {
+        let rank = (p * sorted.len()).div_ceil(100);
+        sorted[rank.max(1) - 1]
+    };
+    _log(severity, format!(
+        "{}: {} (p50={} p95={} p99={})",
+        label, spark, pct(50), pct(95), pct(99),
+    ));
+}
+
+/// A log message, either a string literal or a message which needs
+/// formatting.
+/// 
+/// Literal messages are used as-is, avoiding the allocation of a `String`.
+/// Formatting is deferred until the message is known not to be suppressed.
+#[doc(hidden)]
+pub enum LogMsg<'a> {
+    Literal(&'static str),
+    Formatted(String),
+    Args(std::fmt::Arguments<'a>),
+}
+
+impl<'a> LogMsg<'a> {
+    fn into_str(self) -> std::borrow::Cow<'static, str> {
+        use std::borrow::Cow;
+        match self {
+            LogMsg::Literal(s) => Cow::Borrowed(s),
+            LogMsg::Formatted(s) => Cow::Owned(s),
+            LogMsg::Args(args) => match args.as_str() {
+                Some(s) => Cow::Borrowed(s),
+                None => Cow::Owned(args.to_string()),
+            },
+        }
+    }
+}
+
+impl<'a> From<std::fmt::Arguments<'a>> for LogMsg<'a> {
+    fn from(args: std::fmt::Arguments<'a>) -> Self {
+        LogMsg::Args(args)
+    }
+}
+
+impl From<&'static str> for LogMsg<'_> {
+    fn from(s: &'static str) -> Self {
+        LogMsg::Literal(s)
+    }
+}
+
+impl From<String> for LogMsg<'_> {
+    fn from(s: String) -> Self {
+        LogMsg::Formatted(s)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Output
+
+/// Write log output to stdout/stderr, going through the internal buffers
+/// if a flush interval is set, or to the buffer of the current thread
+/// inside [`buffered_scope`](fn.buffered_scope.html).
+fn write_out(to_stderr: bool, buf: &[u8]) {
+    let scoped = SCOPE_BUF.with(|scope| match &mut *scope.borrow_mut() {
+        Some(msgs) => {
+            msgs.push((to_stderr, buf.to_vec()));
+            true
+        }
+        None => false,
+    });
+    if scoped {
+        return
+    }
+    let _lock = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    write_unlocked(to_stderr, buf);
+}
+
+/// Log output buffered by `buffered_scope`: whether it goes to stderr, and
+/// the encoded line.
+type ScopedMsg = (bool, Vec<u8>);
+
+/// Write log output without taking the write lock.
+fn write_unlocked(to_stderr: bool, buf: &[u8]) {
+    use std::io::Write;
+
+    if NULL_OUTPUT.load(Ordering::Relaxed) {
+        return
+    }
+    let mut active = ACTIVE_WRITER.lock().unwrap();
+    if let Some(w) = &mut *active {
+        let _ = w.write_all(&strip_colors(buf, false));
+    } else {
+        let buf = strip_colors(buf, is_terminal(to_stderr));
+        let buffered = !FLUSH_INTERVAL.read().unwrap().is_zero();
+        let _ = match (buffered, to_stderr) {
+            (true, true) => STDERR_BUF.lock().unwrap().write_all(&buf),
+            (true, false) => STDOUT_BUF.lock().unwrap().write_all(&buf),
+            (false, true) => stderr().lock().write_all(&buf),
+            (false, false) => stdout().lock().write_all(&buf),
+        };
+    }
+    drop(active);
+    let mut writers = WRITERS.lock().unwrap();
+    if !writers.is_empty() {
+        let _ = writers.write_all(&strip_colors(buf, false));
+    }
+}
+
+#[cfg(feature = "colours")]
+fn is_terminal(stderr: bool) -> bool {
+    if stderr { IS_TERMINAL.1 } else { IS_TERMINAL.0 }
+}
+
+#[cfg(not(feature = "colours"))]
+fn is_terminal(_stderr: bool) -> bool {
+    true
+}
+
+/// Strip ANSI codes from `buf` if it is written to something other than a
+/// terminal, unless disabled with `set_color_strip_on_file`.
+#[cfg(feature = "colours")]
+fn strip_colors(buf: &[u8], terminal: bool) -> std::borrow::Cow<'_, [u8]> {
+    use std::borrow::Cow;
+
+    if terminal || !COLOR_STRIP_ON_FILE.load(Ordering::Relaxed) {
+        return Cow::Borrowed(buf)
+    }
+    match std::str::from_utf8(buf).map(console::strip_ansi_codes) {
+        Ok(Cow::Owned(s)) => Cow::Owned(s.into_bytes()),
+        _ => Cow::Borrowed(buf),
+    }
+}
+
+#[cfg(not(feature = "colours"))]
+fn strip_colors(buf: &[u8], _terminal: bool) -> std::borrow::Cow<'_, [u8]> {
+    std::borrow::Cow::Borrowed(buf)
+}
+
+/// A group of writers, which is itself a writer writing everything to all
+/// of them in sequence.
+/// 
+/// A failing writer doesn't stop the others from being written to; the
+/// first error encountered is returned.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::WriterGroup;
+/// use std::io::Write;
+/// 
+/// let mut group = WriterGroup::new(vec![Box::new(std::io::sink()), Box::new(std::io::sink())]);
+/// group.write_all(b"hello\n").unwrap();
+/// ```
+#[derive(Default)]
+pub struct WriterGroup {
+    writers: Vec<Box<dyn std::io::Write + Send + Sync>>,
+}
+
+impl WriterGroup {
+    pub fn new(writers: Vec<Box<dyn std::io::Write + Send + Sync>>) -> Self {
+        WriterGroup { writers }
+    }
+
+    /// Add a writer to the group.
+    pub fn push(&mut self, writer: Box<dyn std::io::Write + Send + Sync>) {
+        self.writers.push(writer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.writers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writers.is_empty()
+    }
+}
+
+impl std::fmt::Debug for WriterGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WriterGroup({} writers)", self.writers.len())
+    }
+}
+
+impl std::io::Write for WriterGroup {
+    /// Write all of `buf` to every writer of the group.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut res = Ok(buf.len());
+        for w in &mut self.writers {
+            if let Err(e) = w.write_all(buf) {
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+        res
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut res = Ok(());
+        for w in &mut self.writers {
+            if let Err(e) = w.flush() {
+                if res.is_ok() {
+                    res = Err(e);
+                }
+            }
+        }
+        res
+    }
+}
+
+/// Write all log output to `writer` instead of stdout/stderr while calling
+/// `f`, restoring the previous writer afterwards, even if `f` panics.
+/// 
+/// The writer is global, not per thread: messages logged by other threads
+/// while `f` is running are also written to it. It is only safe to use
+/// when no other threads are logging, like in single-threaded tests.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::with_custom_writer(Box::new(std::io::sink()), || {
+///     info!("Discarded");
+/// });
+/// info!("Written to stdout");
+/// ```
+pub fn with_custom_writer<F: FnOnce()>(writer: Box<dyn std::io::Write + Send + Sync>, f: F) {
+    struct Restore(Option<Box<dyn std::io::Write + Send + Sync>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let mut active = ACTIVE_WRITER.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(w) = &mut *active {
+                let _ = w.flush();
+            }
+            *active = self.0.take();
+        }
+    }
+
+    let _restore = Restore(ACTIVE_WRITER.lock().unwrap().replace(writer));
+    f();
+}
+
+/// Write a single message to `writer`, formatted with the current global
+/// prefix and format settings, instead of to stdout/stderr.
+/// 
+/// The message is always written: suppression, sampling and interceptors
+/// do not apply.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// let mut out = Vec::new();
+/// mhlog::emit_to(&mut out, Severity::Info, "Request done").unwrap();
+/// assert!(String::from_utf8(out).unwrap().contains("Request done"));
+/// ```
+pub fn emit_to(writer: &mut dyn std::io::Write, severity: Severity, msg: &str) -> std::io::Result<()> {
+    let prefix = if *COMPACT.read().unwrap() {
+        String::new()
+    } else {
+        severity.prefix()
+    };
+    let txt = format_line(severity, &prefix, msg, &[]);
+    writer.write_all(&encode(&txt))
+}
+
+/// Write all log output to `writer` as well as to stdout/stderr.
+/// 
+/// Any number of writers may be added; the output is fanned out to all of
+/// them with a single [`WriterGroup`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// mhlog::add_writer(Box::new(std::io::sink()));
+/// mhlog::info!("Written to stdout and the sink");
+/// mhlog::clear_writers();
+/// ```
+/// 
+/// [`WriterGroup`]: struct.WriterGroup.html
+pub fn add_writer(writer: Box<dyn std::io::Write + Send + Sync>) {
+    WRITERS.lock().unwrap().push(writer);
+}
+
+/// Remove all writers added with [`add_writer`].
+/// 
+/// [`add_writer`]: fn.add_writer.html
+pub fn clear_writers() {
+    let mut writers = WRITERS.lock().unwrap();
+    let _ = std::io::Write::flush(&mut *writers);
+    *writers = WriterGroup::default();
+}
+
+/// Encode log output with the configured output encoding.
+#[cfg(feature = "encoding")]
+fn encode(txt: &str) -> std::borrow::Cow<'_, [u8]> {
+    use std::borrow::Cow;
+    match *ENCODING.read().unwrap() {
+        Encoding::Utf8 => Cow::Borrowed(txt.as_bytes()),
+        Encoding::Utf16Le => Cow::Owned(txt.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()),
+        Encoding::Latin1 => Cow::Owned(txt.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect()),
+    }
+}
+
+#[cfg(not(feature = "encoding"))]
+fn encode(txt: &str) -> std::borrow::Cow<'_, [u8]> {
+    std::borrow::Cow::Borrowed(txt.as_bytes())
+}
+
+/// Output encoding of log messages. Requires the `encoding` feature.
+#[cfg(feature = "encoding")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// UTF-8 (default).
+    Utf8,
+    /// UTF-16, little endian, without byte order mark.
+    Utf16Le,
+    /// ISO-8859-1. Characters which can't be represented are written as `?`.
+    Latin1,
+}
+
+/// Change the encoding of log output. Requires the `encoding` feature.
+/// 
+/// Useful on platforms or consoles which don't support UTF-8. By default
+/// the output is UTF-8.
+/// 
+/// Characters outside the Basic Multilingual Plane are written as
+/// surrogate pairs in UTF-16, and characters which Latin-1 can't represent
+/// are written as one `?` each:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "encoding")] {
+/// use mhlog::{Encoding, Severity};
+/// mhlog::set_compact_mode(true);
+/// 
+/// mhlog::set_output_encoding(Encoding::Utf16Le);
+/// let mut out = Vec::new();
+/// mhlog::emit_to(&mut out, Severity::Info, "aé😀").unwrap();
+/// assert_eq!(out, [0x61, 0x00, 0xE9, 0x00, 0x3D, 0xD8, 0x00, 0xDE, 0x0A, 0x00]);
+/// 
+/// mhlog::set_output_encoding(Encoding::Latin1);
+/// let mut out = Vec::new();
+/// mhlog::emit_to(&mut out, Severity::Info, "aé€😀").unwrap();
+/// assert_eq!(out, b"a\xE9??\n");
+/// # }
+/// ```
+#[cfg(feature = "encoding")]
+pub fn set_output_encoding(enc: Encoding) {
+    let mut e = ENCODING.write().unwrap();
+    *e = enc;
+}
+
+/// Ensure all pending log output has been written before returning.
+/// 
+/// Flushes the internal buffers (see [`set_min_flush_interval`]), stdout,
+/// stderr, and the writers added with [`add_writer`].
+/// 
+/// [`set_min_flush_interval`]: fn.set_min_flush_interval.html
+/// [`add_writer`]: fn.add_writer.html
+pub fn write_barrier() {
+    use std::io::Write;
+
+    let _ = STDOUT_BUF.lock().unwrap().flush();
+    let _ = STDERR_BUF.lock().unwrap().flush();
+    let _ = stdout().flush();
+    let _ = stderr().flush();
+    let _ = WRITERS.lock().unwrap().flush();
+    if let Some(w) = &mut *ACTIVE_WRITER.lock().unwrap() {
+        let _ = w.flush();
+    }
+}
+
+/// Buffer all log output of the current thread while calling `f`, and
+/// write it all at once afterwards, without interleaving with output from
+/// other threads. Output from other threads is unaffected.
+/// 
+/// `capacity` is the number of messages to reserve room for up front. The
+/// buffer is written even if `f` panics. Nested scopes are part of the
+/// outermost scope.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::buffered_scope(16, || {
+///     info!("Step 1");
+///     info!("Step 2");
+/// });
+/// ```
+pub fn buffered_scope<F: FnOnce()>(capacity: usize, f: F) {
+    struct Flush;
+    impl Drop for Flush {
+        fn drop(&mut self) {
+            let msgs = SCOPE_BUF.with(|scope| scope.borrow_mut().take()).unwrap_or_default();
+            let _lock = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            for (to_stderr, buf) in msgs {
+                write_unlocked(to_stderr, &buf);
+            }
+        }
+    }
+
+    let nested = SCOPE_BUF.with(|scope| {
+        let mut scope = scope.borrow_mut();
+        if scope.is_some() {
+            return true
+        }
+        *scope = Some(Vec::with_capacity(capacity));
+        false
+    });
+    if nested {
+        f();
+        return
+    }
+    let _flush = Flush;
+    f();
+}
+
+/// Calls [`write_barrier`] when dropped. See [`flush_on_drop`].
+/// 
+/// [`write_barrier`]: fn.write_barrier.html
+/// [`flush_on_drop`]: fn.flush_on_drop.html
+#[must_use = "the log output is flushed when the guard is dropped"]
+#[derive(Debug)]
+pub struct FlushGuard(());
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        write_barrier();
+    }
+}
+
+/// Get a guard which flushes all pending log output when dropped, on any
+/// exit path of the scope, including `?` and panics.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// fn run() -> std::io::Result<()> {
+///     let _flush = mhlog::flush_on_drop();
+///     mhlog::info!("Running");
+///     std::fs::metadata("/")?;
+///     Ok(())
+/// }
+/// # run().unwrap();
+/// ```
+pub fn flush_on_drop() -> FlushGuard {
+    FlushGuard(())
+}
+
+/// Body of the background flusher thread. Flushes the buffers every
+/// interval, and exits when the interval is set to zero.
+fn flusher(out: Arc<Mutex<BufWriter<Stdout>>>, err: Arc<Mutex<BufWriter<Stderr>>>) {
+    use std::io::Write;
+
+    loop {
+        {
+            let d = FLUSH_INTERVAL.write().unwrap();
+            if d.is_zero() {
+                FLUSHER_RUNNING.store(false, Ordering::SeqCst);
+                return
+            }
+        }
+        let d = *FLUSH_INTERVAL.read().unwrap();
+        std::thread::sleep(d);
+        let _ = out.lock().unwrap().flush();
+        let _ = err.lock().unwrap().flush();
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Config
+
+/// Enable/disable debug messages.
+/// 
+/// By default debug messages are suppressed.
+/// 
+pub fn set_debug(val: bool) {
+    let old = std::mem::replace(&mut *DEBUG.write().unwrap(), val);
+    if old != val {
+        debug_changed(val);
+    }
+}
+
+/// Enable/disable verbose messages.
+/// 
+/// By default verbose messages are suppressed.
+/// 
+/// Equivalent to `set_verbose_level(1)` when enabling, and
+/// `set_verbose_level(0)` when disabling.
+/// 
+pub fn set_verbose(val: bool) {
+    set_verbose_level(val as u32);
+}
+
+/// Set the verbosity level, for tools with multiple levels of verbosity
+/// like `-v`, `-vv` and `-vvv`.
+/// 
+/// Verbose messages are shown when the level is at least 1, and messages
+/// with an explicit level, like `verbose!(level = 2, ...)`, when the level
+/// is at least their level.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::verbose;
+/// mhlog::set_verbose_level(2);
+/// verbose!("Shown");
+/// verbose!(level = 2, "Shown");
+/// verbose!(level = 3, "Not shown");
+/// assert_eq!(mhlog::verbose_level(), 2);
+/// ```
+pub fn set_verbose_level(n: u32) {
+    let old = std::mem::replace(&mut *VERBOSE_LEVEL.write().unwrap(), n);
+    if (old > 0) != (n > 0) {
+        verbose_changed(n > 0);
+    }
+}
+
+/// The current verbosity level. See [`set_verbose_level`].
+/// 
+/// [`set_verbose_level`]: fn.set_verbose_level.html
+pub fn verbose_level() -> u32 {
+    *VERBOSE_LEVEL.read().unwrap()
+}
+
+/// Register a callback which is called with the new value whenever debug
+/// messages are enabled or disabled. Replaces any previous callback.
+/// 
+/// The callback is only called when the value actually changes, after the
+/// change.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// mhlog::set_debug_change_callback(|on| println!("Profiler enabled: {}", on));
+/// mhlog::set_debug(true);
+/// ```
+pub fn set_debug_change_callback(f: fn(bool)) {
+    let mut cb = DEBUG_CALLBACK.write().unwrap();
+    *cb = Some(f);
+}
+
+/// Register a callback which is called with the new value whenever verbose
+/// messages are enabled or disabled. See [`set_debug_change_callback`].
+/// 
+/// [`set_debug_change_callback`]: fn.set_debug_change_callback.html
+pub fn set_verbose_change_callback(f: fn(bool)) {
+    let mut cb = VERBOSE_CALLBACK.write().unwrap();
+    *cb = Some(f);
+}
+
+fn debug_changed(val: bool) {
+    let cb = *DEBUG_CALLBACK.read().unwrap();
+    if let Some(f) = cb {
+        f(val);
+    }
+}
+
+fn verbose_changed(val: bool) {
+    let cb = *VERBOSE_CALLBACK.read().unwrap();
+    if let Some(f) = cb {
+        f(val);
+    }
+}
+
+/// Enable/disable quiet mode.
+/// 
+/// In quiet mode info, verbose and debug messages are all suppressed,
+/// regardless of [`set_verbose()`] and [`set_debug()`]. Only warnings and
+/// errors are written.
+/// 
+/// By default quiet mode is disabled.
+/// 
+/// ```rust
+/// # #[macro_use] extern crate mhlog;
+/// mhlog::set_quiet(true);
+/// info!("Not written");
+/// warn!("Still written");
+/// assert!(mhlog::is_quiet());
+/// ```
+/// 
+/// [`set_verbose()`]: fn.set_verbose.html
+/// [`set_debug()`]: fn.set_debug.html
+pub fn set_quiet(val: bool) {
+    let mut q = QUIET.write().unwrap();
+    *q = val;
+}
+
+/// Suppress all messages except errors during a period of each day, given
+/// as the durations from midnight UTC to its start and end.
+/// 
+/// The period may wrap around midnight, when `end` is before `start`.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// use std::time::Duration;
+/// 
+/// let hour = Duration::from_secs(3600);
+/// // Quiet hours from 02:00 to 06:00 UTC.
+/// mhlog::set_quiet_period(2 * hour, 6 * hour);
+/// ```
+pub fn set_quiet_period(start: Duration, end: Duration) {
+    let mut period = QUIET_PERIOD.write().unwrap();
+    *period = Some((start, end));
+}
+
+/// Remove the quiet period set with [`set_quiet_period`].
+/// 
+/// [`set_quiet_period`]: fn.set_quiet_period.html
+pub fn clear_quiet_period() {
+    let mut period = QUIET_PERIOD.write().unwrap();
+    *period = None;
+}
+
+/// Whether the current time is within the quiet period, if any.
+fn in_quiet_period() -> bool {
+    let (start, end) = match *QUIET_PERIOD.read().unwrap() {
+        Some(period) => period,
+        None => return false,
+    };
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let now = Duration::from_secs(since_epoch.as_secs() % 86400);
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// Whether quiet mode is enabled. See [`set_quiet()`].
+/// 
+/// [`set_quiet()`]: fn.set_quiet.html
+pub fn is_quiet() -> bool {
+    *QUIET.read().unwrap()
+}
+
+/// Choose whether messages of a severity are written to stderr or stdout.
+/// 
+/// The defaults are given by the `only_stderr` and `only_stdout` features,
+/// and otherwise errors and warnings are written to stderr and the rest to
+/// stdout.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// mhlog::set_stderr(Severity::Debug, true);
+/// ```
+pub fn set_stderr(severity: Severity, to_stderr: bool) {
+    let mut table = STDERR_TABLE.write().unwrap();
+    table[severity.index()] = to_stderr;
+}
+
+/// Where log messages are written. See [`set_output_target`].
+/// 
+/// [`set_output_target`]: fn.set_output_target.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputTarget {
+    /// Each severity is written to stderr or stdout as chosen with
+    /// [`set_stderr`](fn.set_stderr.html) (default).
+    #[default]
+    Mixed,
+    /// All messages are written to stderr.
+    AllStderr,
+    /// All messages are written to stdout.
+    AllStdout,
+}
+
+/// Choose where log messages are written at runtime, as an alternative to
+/// the `only_stderr` and `only_stdout` features.
+/// 
+/// With [`OutputTarget::Mixed`] the choice of each severity is used, as
+/// given by the features and [`set_stderr`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::OutputTarget;
+/// mhlog::set_output_target(OutputTarget::AllStderr);
+/// mhlog::info!("Written to stderr");
+/// ```
+/// 
+/// [`OutputTarget::Mixed`]: enum.OutputTarget.html#variant.Mixed
+/// [`set_stderr`]: fn.set_stderr.html
+pub fn set_output_target(target: OutputTarget) {
+    let mut t = OUTPUT_TARGET.write().unwrap();
+    *t = target;
+}
+
+/// Enable/disable discarding all output, for benchmarking code which logs.
+/// 
+/// While enabled, everything which would be written to stdout/stderr, added
+/// writers, custom writers and [`Logger`] writers is written to
+/// `std::io::sink()` instead. Messages are still formatted with prefixes
+/// and all, so only the cost of the actual I/O is removed. Use a
+/// [`BenchmarkLogger`] to also skip the prefixes and formatting.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::set_null_output(true);
+/// for i in 0..1000 {
+///     info!("Iteration {}", i);
+/// }
+/// mhlog::set_null_output(false);
+/// ```
+/// 
+/// [`Logger`]: struct.Logger.html
+/// [`BenchmarkLogger`]: struct.BenchmarkLogger.html
+pub fn set_null_output(enabled: bool) {
+    NULL_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the minimum interval between flushes of log output.
+/// 
+/// When set, log messages are written to an internal buffer which is
+/// flushed by a background thread every `d`, or when the buffer is full.
+/// This coalesces high-frequency writes into fewer syscalls.
+/// 
+/// Setting the interval to zero disables buffering and flushes any
+/// pending output. By default buffering is disabled.
+/// 
+/// Buffered messages which have not yet been flushed are lost if the
+/// process exits without flushing them. Flush them before returning from
+/// `main` with [`write_barrier`], or by holding the guard of
+/// [`flush_on_drop`] in `main`:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// # use std::time::Duration;
+/// let _guard = mhlog::flush_on_drop();
+/// mhlog::set_min_flush_interval(Duration::from_millis(100));
+/// info!("Flushed when main returns");
+/// ```
+/// 
+/// [`bail!()`] and the other exiting macros also flush before exiting.
+/// 
+/// [`write_barrier`]: fn.write_barrier.html
+/// [`flush_on_drop`]: fn.flush_on_drop.html
+/// [`bail!()`]: macro.bail.html
+pub fn set_min_flush_interval(d: Duration) {
+    let mut interval = FLUSH_INTERVAL.write().unwrap();
+    *interval = d;
+    if d.is_zero() {
+        drop(interval);
+        write_barrier();
+    } else if !FLUSHER_RUNNING.swap(true, Ordering::SeqCst) {
+        let out = Arc::clone(&STDOUT_BUF);
+        let err = Arc::clone(&STDERR_BUF);
+        std::thread::spawn(move || flusher(out, err));
+    }
+}
+
+/// Return a human-readable report of the current configuration.
+/// 
+/// Useful for figuring out why log messages are not appearing:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// info!("{}", mhlog::summary());
+/// ```
+pub fn summary() -> String {
+    use std::fmt::Write;
+
+    let mut s = String::from("mhlog configuration:\n");
+    let _ = writeln!(s, "  verbose: {}", verbose_level());
+    let _ = writeln!(s, "  debug:   {}", *DEBUG.read().unwrap());
+    if is_quiet() {
+        let _ = writeln!(s, "  quiet:   true");
+    }
+    if let Some(var) = *LEVEL_ENV_VAR.read().unwrap() {
+        let _ = writeln!(s, "  level:   from ${}", var);
+    }
+    {
+        use Severity::*;
+        let sup = SUPPRESSED.read().unwrap();
+        let names: Vec<String> = [Err, Warn, Info, Verbose, Debug].iter()
+            .filter(|sev| sup[sev.index()])
+            .map(|sev| format!("{:?}", sev))
+            .collect();
+        if !names.is_empty() {
+            let _ = writeln!(s, "  suppressed: {}", names.join(", "));
+        }
+    }
+    {
+        let pre = PREFIXES.read().unwrap();
+        let _ = writeln!(s, "  prefixes:");
+        for line in pre.to_string().lines() {
+            let _ = writeln!(s, "    {}", line);
+        }
+        if pre.universal_fn.is_some() {
+            let _ = writeln!(s, "    all:   universal dynamic fn");
+        }
+    }
+    let interval = *FLUSH_INTERVAL.read().unwrap();
+    if interval.is_zero() {
+        let _ = writeln!(s, "  writer:  stdout/stderr (unbuffered)");
+    } else {
+        let _ = writeln!(s, "  writer:  stdout/stderr (buffered, flush interval {:?})", interval);
+    }
+    let n = WRITERS.lock().unwrap().len();
+    if n > 0 {
+        let _ = writeln!(s, "  writers: {} additional writer(s)", n);
+    }
+    #[cfg(feature = "colours")]
+    let _ = writeln!(s, "  colours: {:?}", *COLOR_MODE.read().unwrap());
+    let n = INTERCEPTORS.read().unwrap().len();
+    if n > 0 {
+        let _ = writeln!(s, "  hooks:   {} interceptor(s)", n);
+    }
+    let stderr: Vec<String> = {
+        use Severity::*;
+        [Err, Warn, Info, Verbose, Debug].iter()
+            .map(|sev| format!("{:?} -> {}", sev, if sev.to_stderr() { "stderr" } else { "stdout" }))
+            .collect()
+    };
+    let _ = write!(s, "  output:  {}", stderr.join(", "));
+    s
+}
+
+/// Change the severity of deprecation notices from [`warn_deprecated!()`].
+/// 
+/// By default deprecation notices are printed as warnings.
+/// 
+/// [`warn_deprecated!()`]: macro.warn_deprecated.html
+pub fn set_deprecation_severity(severity: Severity) {
+    let mut sev = DEPRECATION_SEVERITY.write().unwrap();
+    *sev = severity;
+}
+
+/// Enable/disable compact mode, where messages are printed without any prefix.
+/// 
+/// Useful when the consumer of the log output already adds severity
+/// information, such as the systemd journal. The configured prefixes are
+/// kept, and used again when compact mode is disabled.
+/// 
+/// By default compact mode is disabled.
+/// 
+pub fn set_compact_mode(enabled: bool) {
+    let mut c = COMPACT.write().unwrap();
+    *c = enabled;
+    PREFIX_GEN.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Enable/disable message IDs, for correlating log messages.
+/// 
+/// When enabled every log line gets a unique, monotonically increasing ID,
+/// written as hex between the prefix and the message:
+/// 
+/// ```text
+/// [*] 0000001a hello world
+/// ```
+/// 
+/// By default message IDs are disabled.
+/// 
+pub fn set_message_id(enabled: bool) {
+    MESSAGE_IDS.store(enabled, Ordering::Relaxed);
+}
+
+/// Get the ID of the last written message, or 0 if no message has been
+/// written with an ID. See [`set_message_id()`].
+/// 
+/// ```rust
+/// # #[macro_use] extern crate mhlog;
+/// mhlog::set_message_id(true);
+/// err!("Request failed");
+/// let id = mhlog::get_last_message_id();
+/// assert_ne!(id, 0);
+/// ```
+/// 
+/// [`set_message_id()`]: fn.set_message_id.html
+pub fn get_last_message_id() -> u64 {
+    LAST_MESSAGE_ID.load(Ordering::SeqCst)
+}
+
+/// Set the registry of error code names used by [`err_code!()`].
+/// 
+/// [`err_code!()`]: macro.err_code.html
+pub fn set_error_code_registry(registry: HashMap<u32, &'static str>) {
+    let mut codes = ERROR_CODES.write().unwrap();
+    *codes = registry;
+}
+
+/// Change the severity of messages from [`checkpoint!()`].
+/// 
+/// By default checkpoints are printed as debug messages.
+/// 
+/// [`checkpoint!()`]: macro.checkpoint.html
+pub fn set_checkpoint_severity(severity: Severity) {
+    let mut sev = CHECKPOINT_SEVERITY.write().unwrap();
+    *sev = severity;
+}
+
+/// Change the number of bytes per row printed by [`debug_hex!()`].
+/// 
+/// The default is 16 bytes per row.
+/// 
+/// [`debug_hex!()`]: macro.debug_hex.html
+pub fn set_hex_row_width(n: usize) {
+    HEX_ROW_WIDTH.store(n.max(1), Ordering::Relaxed);
+}
+
+/// Change the width of the dividers printed by [`info_section!()`].
+/// 
+/// The default is 60 characters.
+/// 
+/// [`info_section!()`]: macro.info_section.html
+pub fn set_section_width(n: usize) {
+    SECTION_WIDTH.store(n, Ordering::Relaxed);
+}
+
+/// Suppress all messages of the given severity while calling `f`.
+/// 
+/// The previous suppression state is restored afterwards, also if `f`
+/// panics. Note that the suppression is global, and affects messages
+/// from all threads while `f` is running.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{warn, Severity};
+/// mhlog::with_suppression(Severity::Warn, || {
+///     warn!("This will not be printed.");
+/// });
+/// warn!("This will be printed.");
+/// ```
+pub fn with_suppression<F: FnOnce()>(severity: Severity, f: F) {
+    let _guard = SuppressionGuard::new(severity);
+    f();
+}
+
+/// Suppresses a severity while alive, restoring the previous state on drop.
+struct SuppressionGuard {
+    severity: Severity,
+    previous: bool,
+}
+
+impl SuppressionGuard {
+    fn new(severity: Severity) -> Self {
+        let mut sup = SUPPRESSED.write().unwrap();
+        let previous = sup[severity.index()];
+        sup[severity.index()] = true;
+        SuppressionGuard { severity, previous }
+    }
+}
+
+impl Drop for SuppressionGuard {
+    fn drop(&mut self) {
+        // Don't panic on a poisoned lock while already unwinding.
+        if let Ok(mut sup) = SUPPRESSED.write() {
+            sup[self.severity.index()] = self.previous;
+        }
+    }
+}
+
+/// Change the error prefix to a new static value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn error_prefix_str(s: String) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (f, old) = pre.slots(Severity::Err);
+    (*f, std::mem::replace(old, s))
+}
+
+/// Change the warning prefix to a new static value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn warning_prefix_str(s: String) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (f, old) = pre.slots(Severity::Warn);
+    (*f, std::mem::replace(old, s))
+}
+
+/// Change the info prefix to a new static value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn info_prefix_str(s: String) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (f, old) = pre.slots(Severity::Info);
+    (*f, std::mem::replace(old, s))
+}
+
+/// Change the debug prefix to a new static value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn debug_prefix_str(s: String) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (f, old) = pre.slots(Severity::Debug);
+    (*f, std::mem::replace(old, s))
+}
+
+/// Change the error prefix to a dynamic value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn error_prefix_fn(f: PrefixFn) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (old, s) = pre.slots(Severity::Err);
+    (old.replace(f), s.clone())
+}
+
+/// Change the warning prefix to a dynamic value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn warning_prefix_fn(f: PrefixFn) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (old, s) = pre.slots(Severity::Warn);
+    (old.replace(f), s.clone())
+}
+
+/// Change the info prefix to a dynamic value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn info_prefix_fn(f: PrefixFn) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (old, s) = pre.slots(Severity::Info);
+    (old.replace(f), s.clone())
+}
+
+/// Change the debug prefix to a dynamic value.
+/// 
+/// Returns the previous prefix, which may be restored with [`restore_prefix`].
+/// 
+/// [`restore_prefix`]: fn.restore_prefix.html
+pub fn debug_prefix_fn(f: PrefixFn) -> OldPrefix {
+    let mut pre = prefixes_mut();
+    let (old, s) = pre.slots(Severity::Debug);
+    (old.replace(f), s.clone())
+}
+
+/// Change the prefix of a severity for the current thread only.
+/// 
+/// The thread prefix takes priority over the global static and dynamic
+/// prefixes. As with the global prefixes, verbose messages use the info
+/// prefix.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, Severity};
+/// std::thread::spawn(|| {
+///     mhlog::set_thread_prefix(Severity::Info, "[worker-1]".to_string());
+///     info!("Hello from the worker");
+/// }).join().unwrap();
+/// info!("Hello from the main thread");
+/// ```
+pub fn set_thread_prefix(severity: Severity, prefix: String) {
+    let key = if severity == Severity::Verbose { Severity::Info } else { severity };
+    THREAD_PREFIX.with(|map| map.borrow_mut().insert(key, prefix));
+}
+
+/// Remove the thread prefix of a severity, set by [`set_thread_prefix`].
+/// 
+/// [`set_thread_prefix`]: fn.set_thread_prefix.html
+pub fn clear_thread_prefix(severity: Severity) {
+    let key = if severity == Severity::Verbose { Severity::Info } else { severity };
+    THREAD_PREFIX.with(|map| map.borrow_mut().remove(&key));
+}
+
+/// Change the brackets around the static prefixes.
+/// 
+/// Prefixes which are enclosed in the current brackets (`[` and `]` by
+/// default) get them replaced. Other prefixes, and dynamic prefixes, are
+/// not affected.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::err;
+/// mhlog::set_prefix_bracket("<", ">");
+/// err!("Prefixed with <!!>");
+/// ```
+pub fn set_prefix_bracket(open: &'static str, close: &'static str) {
+    let mut guard = prefixes_mut();
+    let pre = &mut *guard;
+    let (old_open, old_close) = pre.bracket;
+    for s in [&mut pre.err_str, &mut pre.warn_str, &mut pre.info_str, &mut pre.debug_str].iter_mut() {
+        if s.len() >= old_open.len() + old_close.len()
+            && s.starts_with(old_open) && s.ends_with(old_close)
+        {
+            let inner = &s[old_open.len()..s.len() - old_close.len()];
+            **s = format!("{}{}{}", open, inner, close);
+        }
+    }
+    pre.bracket = (open, close);
+}
+
+/// Change the case of all static prefixes.
+/// 
+/// Dynamic prefixes are not affected. The original prefix strings are kept,
+/// so changing back to `PrefixCase::AsIs` restores them.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{err, PrefixCase};
+/// mhlog::error_prefix_str("[Error]".to_string());
+/// mhlog::set_prefix_case(PrefixCase::Upper);
+/// err!("Prefixed with [ERROR]");
+/// ```
+pub fn set_prefix_case(case: PrefixCase) {
+    let mut pre = prefixes_mut();
+    pre.case = case;
+}
+
+/// Change the prefix of all severities to a dynamic value, generated by a
+/// single function.
+/// 
+/// The universal prefix function takes priority over the prefixes set for
+/// each severity, until removed with [`clear_universal_prefix_fn`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, Severity};
+/// mhlog::set_universal_prefix_fn(|sev| format!("{:?}:", sev).to_uppercase());
+/// info!("Prefixed with INFO:");
+/// ```
+/// 
+/// [`clear_universal_prefix_fn`]: fn.clear_universal_prefix_fn.html
+pub fn set_universal_prefix_fn(f: UniversalPrefixFn) {
+    let mut pre = prefixes_mut();
+    pre.universal_fn = Some(f);
+}
+
+/// Remove the universal prefix function set with [`set_universal_prefix_fn`],
+/// falling back to the prefixes set for each severity.
+/// 
+/// [`set_universal_prefix_fn`]: fn.set_universal_prefix_fn.html
+pub fn clear_universal_prefix_fn() {
+    let mut pre = prefixes_mut();
+    pre.universal_fn = None;
+}
+
+/// Change the error prefix to a [`PrefixTemplate`].
+/// 
+/// The template takes precedence over the static and dynamic prefix until
+/// it is removed with [`clear_prefix_template`].
+/// 
+/// [`PrefixTemplate`]: struct.PrefixTemplate.html
+/// [`clear_prefix_template`]: fn.clear_prefix_template.html
+pub fn set_error_prefix_template(t: PrefixTemplate) {
+    let mut pre = prefixes_mut();
+    pre.templates.insert(Severity::Err, t);
+}
+
+/// Change the warning prefix to a [`PrefixTemplate`].
+/// 
+/// The template takes precedence over the static and dynamic prefix until
+/// it is removed with [`clear_prefix_template`].
+/// 
+/// [`PrefixTemplate`]: struct.PrefixTemplate.html
+/// [`clear_prefix_template`]: fn.clear_prefix_template.html
+pub fn set_warning_prefix_template(t: PrefixTemplate) {
+    let mut pre = prefixes_mut();
+    pre.templates.insert(Severity::Warn, t);
+}
+
+/// Change the info prefix to a [`PrefixTemplate`].
+/// 
+/// The template takes precedence over the static and dynamic prefix until
+/// it is removed with [`clear_prefix_template`].
+/// 
+/// [`PrefixTemplate`]: struct.PrefixTemplate.html
+/// [`clear_prefix_template`]: fn.clear_prefix_template.html
+pub fn set_info_prefix_template(t: PrefixTemplate) {
+    let mut pre = prefixes_mut();
+    pre.templates.insert(Severity::Info, t);
+}
+
+/// Change the debug prefix to a [`PrefixTemplate`].
+/// 
+/// The template takes precedence over the static and dynamic prefix until
+/// it is removed with [`clear_prefix_template`].
+/// 
+/// [`PrefixTemplate`]: struct.PrefixTemplate.html
+/// [`clear_prefix_template`]: fn.clear_prefix_template.html
+pub fn set_debug_prefix_template(t: PrefixTemplate) {
+    let mut pre = prefixes_mut();
+    pre.templates.insert(Severity::Debug, t);
+}
+
+/// Remove the prefix template of a severity, falling back to its static or
+/// dynamic prefix.
+pub fn clear_prefix_template(severity: Severity) {
+    let key = if severity == Severity::Verbose { Severity::Info } else { severity };
+    let mut pre = prefixes_mut();
+    pre.templates.remove(&key);
+}
+
+/// Change the prefixes of all severities to the same dynamic value.
+/// 
+/// Equivalent to calling [`error_prefix_fn`], [`warning_prefix_fn`],
+/// [`info_prefix_fn`] and [`debug_prefix_fn`], but atomic: no message is
+/// logged with only some of the prefixes changed.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, warn};
+/// mhlog::set_prefix_fn_all(|| "[app]".to_string());
+/// info!("Same prefix");
+/// warn!("Same prefix");
+/// mhlog::clear_prefix_fn_all();
+/// ```
+/// 
+/// [`error_prefix_fn`]: fn.error_prefix_fn.html
+/// [`warning_prefix_fn`]: fn.warning_prefix_fn.html
+/// [`info_prefix_fn`]: fn.info_prefix_fn.html
+/// [`debug_prefix_fn`]: fn.debug_prefix_fn.html
+pub fn set_prefix_fn_all(f: PrefixFn) {
+    let mut pre = prefixes_mut();
+    for sev in &[Severity::Err, Severity::Warn, Severity::Info, Severity::Debug] {
+        *pre.slots(*sev).0 = Some(f);
+    }
+}
+
+/// Remove the dynamic prefixes of all severities, falling back to their
+/// static prefixes. Atomic, like [`set_prefix_fn_all`].
+/// 
+/// [`set_prefix_fn_all`]: fn.set_prefix_fn_all.html
+pub fn clear_prefix_fn_all() {
+    let mut pre = prefixes_mut();
+    for sev in &[Severity::Err, Severity::Warn, Severity::Info, Severity::Debug] {
+        *pre.slots(*sev).0 = None;
+    }
+}
+
+/// The current prefix of a severity, static or dynamic, as it would be
+/// written now. Useful for custom formatters.
+/// 
+/// Changes of the prefix are visible immediately:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// mhlog::warning_prefix_str("WARN".to_string());
+/// assert_eq!(mhlog::prefix_for(Severity::Warn), "WARN");
+/// 
+/// mhlog::warning_prefix_fn(|| "DYN".to_string());
+/// assert_eq!(mhlog::prefix_for(Severity::Warn), "DYN");
+/// 
+/// // Verbose messages use the info prefix.
+/// mhlog::info_prefix_str("INFO".to_string());
+/// assert_eq!(mhlog::prefix_for(Severity::Verbose), "INFO");
+/// ```
+pub fn prefix_for(severity: Severity) -> String {
+    severity.prefix()
+}
+
+/// The width of the prefix of a severity, as it would be written now.
+/// 
+/// With the `unicode-width` feature the displayed width is returned,
+/// otherwise the length in bytes. In compact mode the width is 0.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// mhlog::info_prefix_str("[*]".to_string());
+/// assert_eq!(mhlog::prefix_width(Severity::Info), 3);
+/// ```
+pub fn prefix_width(severity: Severity) -> usize {
+    if *COMPACT.read().unwrap() {
+        return 0
+    }
+    display_width(&severity.prefix())
+}
+
+/// The displayed width of `s` with the `unicode-width` feature, otherwise
+/// its length in bytes.
+fn display_width(s: &str) -> usize {
+    #[cfg(feature = "unicode-width")]
+    return unicode_width::UnicodeWidthStr::width(s);
+    #[cfg(not(feature = "unicode-width"))]
+    return s.len();
+}
+
+/// How prefixes are padded to align messages. See [`set_prefix_padding`].
+/// 
+/// [`set_prefix_padding`]: fn.set_prefix_padding.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaddingMode {
+    /// No padding (default).
+    #[default]
+    None,
+    /// Pad prefixes with spaces on the right, left-aligning them.
+    RightPad,
+    /// Pad prefixes with spaces on the left, right-aligning them.
+    LeftPad,
+}
+
+/// Pad prefixes to the [`max_prefix_width`], so the messages of all
+/// severities are aligned:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{err, info, PaddingMode};
+/// mhlog::set_prefix_padding(PaddingMode::RightPad);
+/// err!("Aligned");  // Prints: [!!] Aligned
+/// info!("Aligned"); // Prints: [*]  Aligned
+/// ```
+/// 
+/// Empty prefixes, as in compact mode, are not padded.
+/// 
+/// [`max_prefix_width`]: fn.max_prefix_width.html
+pub fn set_prefix_padding(padding: PaddingMode) {
+    let mut p = PADDING.write().unwrap();
+    *p = padding;
+    PREFIX_GEN.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Pad a prefix according to the padding mode.
+fn pad_prefix(prefix: &str) -> std::borrow::Cow<'_, str> {
+    use std::borrow::Cow;
+
+    let mode = *PADDING.read().unwrap();
+    if mode == PaddingMode::None || prefix.is_empty() {
+        return Cow::Borrowed(prefix)
+    }
+    let pad = " ".repeat(pad_width().saturating_sub(display_width(prefix)));
+    match mode {
+        PaddingMode::RightPad => Cow::Owned(format!("{}{}", prefix, pad)),
+        PaddingMode::LeftPad => Cow::Owned(format!("{}{}", pad, prefix)),
+        PaddingMode::None => Cow::Borrowed(prefix),
+    }
+}
+
+/// The width prefixes are padded to: the largest width of the configured
+/// prefixes, computed again only when the prefixes, compact mode or padding
+/// change. Dynamic prefixes are measured once, so the padding doesn't
+/// change from line to line, and per-thread prefixes are not considered.
+fn pad_width() -> usize {
+    use Severity::*;
+
+    let gen = PREFIX_GEN.load(Ordering::SeqCst);
+    let mut cache = PAD_WIDTH.lock().unwrap();
+    if cache.0 != gen {
+        let width = if *COMPACT.read().unwrap() {
+            0
+        } else {
+            let pre = PREFIXES.read().unwrap();
+            [Err, Warn, Info, Verbose, Debug].iter()
+                .map(|&sev| display_width(&pre.get(sev)))
+                .max()
+                .unwrap_or(0)
+        };
+        *cache = (gen, width);
+    }
+    cache.1
+}
+
+/// The largest [`prefix_width`] of all severities.
+/// 
+/// [`prefix_width`]: fn.prefix_width.html
+pub fn max_prefix_width() -> usize {
+    use Severity::*;
+    [Err, Warn, Info, Verbose, Debug].iter()
+        .map(|&sev| prefix_width(sev))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Lock the prefixes for changing them, invalidating the padding width.
+fn prefixes_mut() -> std::sync::RwLockWriteGuard<'static, LogPrefixes> {
+    let guard = PREFIXES.write().unwrap();
+    PREFIX_GEN.fetch_add(1, Ordering::SeqCst);
+    guard
+}
+
+/// Restore a prefix returned by one of the `*_prefix_str` or `*_prefix_fn`
+/// functions.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, Severity};
+/// let old = mhlog::info_prefix_str("Info:".to_string());
+/// info!("Custom prefix");
+/// mhlog::restore_prefix(Severity::Info, old);
+/// info!("Default prefix");
+/// ```
+pub fn restore_prefix(severity: Severity, old: OldPrefix) {
+    let mut pre = prefixes_mut();
+    let (f, s) = pre.slots(severity);
+    *f = old.0;
+    *s = old.1;
+}
+
+// -----------------------------------------------------------------------------
+// Formatters
+
+/// Combines the prefix and message of a log line. See [`set_formatter`].
+/// 
+/// Fields, context, colouring and the trailing newline are added to the
+/// result by mhlog.
+/// 
+/// [`set_formatter`]: fn.set_formatter.html
+pub trait Formatter: Send + Sync {
+    fn format(&self, severity: Severity, prefix: &str, msg: &str) -> String;
+}
+
+/// The default formatter: the prefix and message separated by a space, or
+/// only the message if the prefix is empty.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultFormatter;
+
+impl Formatter for DefaultFormatter {
+    fn format(&self, _: Severity, prefix: &str, msg: &str) -> String {
+        if prefix.is_empty() {
+            msg.to_string()
+        } else {
+            format!("{} {}", prefix, msg)
+        }
+    }
+}
+
+/// A formatter substituting the tokens `{prefix}`, `{msg}`, `{level}` and
+/// `{time}` of a template. Other text, including unknown tokens, is kept
+/// as-is.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, TemplateFormatter};
+/// mhlog::set_formatter(Box::new(TemplateFormatter {
+///     template: "{time} {prefix} {msg}".to_string(),
+/// }));
+/// info!("Templated"); // Prints: 2024-05-17T09:41:07Z [*] Templated
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateFormatter {
+    pub template: String,
+}
+
+impl Formatter for TemplateFormatter {
+    fn format(&self, severity: Severity, prefix: &str, msg: &str) -> String {
+        let mut out = String::with_capacity(self.template.len() + msg.len());
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let token = rest.find('}').map_or("", |end| &rest[..=end]);
+            match token {
+                "{prefix}" => out.push_str(prefix),
+                "{msg}" => out.push_str(msg),
+                "{level}" => out.push_str(&format!("{:?}", severity).to_lowercase()),
+                "{time}" => out.push_str(&utc_now()),
+                _ => {
+                    out.push('{');
+                    rest = &rest[1..];
+                    continue
+                }
+            }
+            rest = &rest[token.len()..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Replace the formatter combining the prefix and message of every log
+/// line. See [`Formatter`].
+/// 
+/// [`Formatter`]: trait.Formatter.html
+pub fn set_formatter(f: Box<dyn Formatter>) {
+    let mut formatter = FORMATTER.write().unwrap();
+    *formatter = Some(f);
+}
+
+// -----------------------------------------------------------------------------
+// Interceptors
+
+/// Middleware between the logging macros and the output.
+/// 
+/// An interceptor may modify the message, or suppress it by returning
+/// `false`. It is implemented for all matching closures.
+pub trait Interceptor: Send + Sync {
+    fn intercept(&self, severity: Severity, msg: &mut String) -> bool;
+}
+
+impl<F> Interceptor for F
+where
+    F: Fn(Severity, &mut String) -> bool + Send + Sync,
+{
+    fn intercept(&self, severity: Severity, msg: &mut String) -> bool {
+        self(severity, msg)
+    }
+}
+
+/// Add an interceptor, which is called for every message which is not
+/// suppressed.
+/// 
+/// Interceptors are called in the order they were added. If any of them
+/// returns `false` the message is dropped, and the remaining interceptors
+/// are not called.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, Severity};
+/// mhlog::add_interceptor(Box::new(|_: Severity, msg: &mut String| {
+///     *msg = msg.replace("secret", "******");
+///     !msg.contains("noise")
+/// }));
+/// info!("The password is secret"); // Prints: [*] The password is ******
+/// info!("Some noise");             // Prints nothing
+/// ```
+pub fn add_interceptor(i: Box<dyn Interceptor>) {
+    let mut interceptors = INTERCEPTORS.write().unwrap();
+    interceptors.push(i);
+}
+
+/// Function signature for message transforms. See
+/// [`set_message_transform`](fn.set_message_transform.html).
+pub type MessageTransform = Box<dyn Fn(Severity, String) -> String + Send + Sync>;
+
+/// Transform the body of every message which is not suppressed, before the
+/// prefix is added. Replaces any previous transform.
+/// 
+/// Useful for escaping or sanitizing messages, like removing control
+/// characters from user-provided strings. The transform runs before the
+/// interceptors.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::set_message_transform(Box::new(|_, msg| {
+///     msg.chars().filter(|c| !c.is_control()).collect()
+/// }));
+/// info!("Bell\x07 removed"); // Prints: [*] Bell removed
+/// mhlog::clear_message_transform();
+/// ```
+pub fn set_message_transform(f: MessageTransform) {
+    let mut t = MESSAGE_TRANSFORM.write().unwrap();
+    *t = Some(f);
+}
+
+/// Remove the transform set with [`set_message_transform`].
+/// 
+/// [`set_message_transform`]: fn.set_message_transform.html
+pub fn clear_message_transform() {
+    let mut t = MESSAGE_TRANSFORM.write().unwrap();
+    *t = None;
+}
+
+// -----------------------------------------------------------------------------
+// Redirects
+
+/// Error returned by [`redirect`] when the redirect would be circular.
+/// 
+/// [`redirect`]: fn.redirect.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectError {
+    pub from: Severity,
+    pub to: Severity,
+}
+
+impl std::fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "circular redirect from {:?} to {:?}", self.from, self.to)
+    }
+}
+
+impl std::error::Error for RedirectError {}
+
+/// Log all messages of severity `from` as severity `to` instead.
+/// 
+/// The redirect is applied before anything else, so the redirected message
+/// gets the prefix, suppression, colour and output (stdout or stderr) of
+/// the new severity. This may be used to silence noisy errors in
+/// production by redirecting them to a suppressed severity, like verbose.
+/// 
+/// Redirects may be chained, but not circular:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{debug, Severity};
+/// mhlog::redirect(Severity::Debug, Severity::Info).unwrap();
+/// debug!("Printed as info");
+/// assert!(mhlog::redirect(Severity::Info, Severity::Debug).is_err());
+/// mhlog::clear_redirect(Severity::Debug);
+/// ```
+pub fn redirect(from: Severity, to: Severity) -> Result<(), RedirectError> {
+    let mut map = REDIRECTS.write().unwrap();
+    let mut sev = to;
+    loop {
+        if sev == from {
+            return Err(RedirectError { from, to })
+        }
+        match map.get(&sev) {
+            Some(&next) => sev = next,
+            None => break,
+        }
+    }
+    map.insert(from, to);
+    Ok(())
+}
+
+/// Remove the redirect of severity `from`. See [`redirect`].
+/// 
+/// [`redirect`]: fn.redirect.html
+pub fn clear_redirect(from: Severity) {
+    let mut map = REDIRECTS.write().unwrap();
+    map.remove(&from);
+}
+
+/// Re-map messages of severity `from` to severity `to`, e.g. to silence
+/// specific errors in production by mapping them to verbose.
+/// 
+/// The mapping is applied at the very start of logging, before the
+/// suppression check, prefix lookup and choice of output, which is what
+/// [`redirect`] does; this is the same mapping under the name used for
+/// this purpose. Remove it with [`clear_redirect`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{err, Severity};
+/// mhlog::map_severity(Severity::Err, Severity::Verbose).unwrap();
+/// err!("Not shown, since verbose is disabled");
+/// assert_eq!(Severity::Err.redirected(), Severity::Verbose);
+/// ```
+/// 
+/// [`redirect`]: fn.redirect.html
+/// [`clear_redirect`]: fn.clear_redirect.html
+pub fn map_severity(from: Severity, to: Severity) -> Result<(), RedirectError> {
+    redirect(from, to)
+}
+
+// -----------------------------------------------------------------------------
+// Context
+
+/// Per-thread stack of key-value pairs appended to every log message.
+#[derive(Debug, Default)]
+struct LogContext {
+    fields: Vec<(String, String)>,
+}
+
+impl LogContext {
+    fn push(&mut self, key: String, value: String) {
+        self.fields.push((key, value));
+    }
+
+    fn pop(&mut self) -> Option<(String, String)> {
+        self.fields.pop()
+    }
+}
+
+/// Push a key-value pair to the log context of the current thread.
+/// 
+/// All messages logged from this thread are followed by the context
+/// pairs, as `key=value`, until the pair is removed with [`ctx_pop`].
+/// The context is never shared with other threads.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::ctx_push("req_id", "abc123");
+/// info!("Handling request");
+/// // Prints: [*] Handling request req_id=abc123
+/// mhlog::ctx_pop();
+/// ```
+/// 
+/// [`ctx_pop`]: fn.ctx_pop.html
+pub fn ctx_push<K: Into<String>, V: Into<String>>(key: K, value: V) {
+    CTX.with(|ctx| ctx.borrow_mut().push(key.into(), value.into()));
+}
+
+/// Pop the most recently pushed key-value pair from the log context of
+/// the current thread.
+pub fn ctx_pop() -> Option<(String, String)> {
+    CTX.with(|ctx| ctx.borrow_mut().pop())
+}
+
+/// Set key-value pairs which are appended to every log message of all
+/// threads, like `service=api region=us-east-1`. Replaces any previous
+/// global fields.
+/// 
+/// The global fields are written after the fields of the message itself,
+/// sorted by key. Fields of the message take priority over global fields
+/// with the same key.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// mhlog::set_global_fields(vec![("service", "api"), ("region", "us-east-1")]);
+/// info!("Started"); // Prints: [*] Started region=us-east-1 service=api
+/// mhlog::clear_global_fields();
+/// ```
+pub fn set_global_fields<I, K, V>(fields: I)
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: Into<String>,
+    V: Into<String>,
+{
+    let map = fields.into_iter().map(|(k, v)| (k.into(), v.into())).collect();
+    *GLOBAL_FIELDS.write().unwrap() = map;
+}
+
+/// The global fields set with [`set_global_fields`].
+/// 
+/// [`set_global_fields`]: fn.set_global_fields.html
+pub fn get_global_fields() -> HashMap<String, String> {
+    GLOBAL_FIELDS.read().unwrap().clone()
+}
+
+/// Remove all global fields. See [`set_global_fields`].
+/// 
+/// [`set_global_fields`]: fn.set_global_fields.html
+pub fn clear_global_fields() {
+    GLOBAL_FIELDS.write().unwrap().clear();
+}
+
+/// Pops the log context when dropped. Used by [`ctx_scope!()`].
+/// 
+/// [`ctx_scope!()`]: macro.ctx_scope.html
+#[doc(hidden)]
+pub struct CtxGuard(());
+
+impl Drop for CtxGuard {
+    fn drop(&mut self) {
+        ctx_pop();
+    }
+}
+
+#[doc(hidden)]
+pub fn _ctx_guard<K: Into<String>, V: Into<String>>(key: K, value: V) -> CtxGuard {
+    ctx_push(key, value);
+    CtxGuard(())
+}
+
+// -----------------------------------------------------------------------------
+// Indentation
+
+/// Set the indentation level of messages logged from the current thread.
+/// Each level indents the message by two spaces, after the prefix.
+pub fn set_indent_level(n: usize) {
+    INDENT.with(|i| i.set(n));
+}
+
+/// Increase the indentation level of the current thread by one.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// info!("Building");
+/// mhlog::indent();
+/// info!("Compiling");
+/// mhlog::dedent();
+/// info!("Done");
+/// ```
+pub fn indent() {
+    INDENT.with(|i| i.set(i.get() + 1));
+}
+
+/// Decrease the indentation level of the current thread by one, if it is
+/// indented.
+pub fn dedent() {
+    INDENT.with(|i| i.set(i.get().saturating_sub(1)));
+}
+
+/// Run `f` with the indentation level of the current thread increased by
+/// one. The indentation is restored even if `f` panics.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// info!("Building");
+/// mhlog::with_indent(|| info!("Compiling"));
+/// info!("Done");
+/// ```
+pub fn with_indent<F: FnOnce()>(f: F) {
+    struct Dedent;
+    impl Drop for Dedent {
+        fn drop(&mut self) {
+            dedent();
+        }
+    }
+
+    indent();
+    let _guard = Dedent;
+    f();
+}
+
+/// Print a group header with the info prefix, and indent the messages of
+/// the current thread until the returned guard is dropped, which prints
+/// the group footer.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// {
+///     let _group = mhlog::group("database");
+///     info!("Connected");
+/// }
+/// // Prints:
+/// // [*] === database ===
+/// // [*]   Connected
+/// // [*] === end database ===
+/// ```
+/// 
+/// Groups may be nested, indenting further like [`indent()`]. The group
+/// depth is added to the indentation level, and isn't changed by
+/// [`set_indent_level`], [`indent()`] or [`dedent()`]:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// # #[cfg(all(feature = "test-utils", unix))] {
+/// let out = mhlog::with_stdout_capture(|| {
+///     let _group = mhlog::group("database");
+///     mhlog::set_indent_level(0);
+///     info!("Connected");
+/// });
+/// assert!(out.contains("[*]   Connected\n[*] === end database ===\n"));
+/// # }
+/// ```
+/// 
+/// [`indent()`]: fn.indent.html
+/// [`dedent()`]: fn.dedent.html
+/// [`set_indent_level`]: fn.set_indent_level.html
+pub fn group(key: &str) -> GroupGuard {
+    _log(Severity::Info, format!("=== {} ===", key));
+    GROUP_DEPTH.with(|d| d.set(d.get() + 1));
+    GroupGuard { key: key.to_string() }
+}
+
+/// Ends a group when dropped. See [`group()`].
+/// 
+/// [`group()`]: fn.group.html
+#[must_use = "the group ends when the guard is dropped"]
+#[derive(Debug)]
+pub struct GroupGuard {
+    key: String,
+}
+
+impl Drop for GroupGuard {
+    fn drop(&mut self) {
+        GROUP_DEPTH.with(|d| d.set(d.get().saturating_sub(1)));
+        _log(Severity::Info, format!("=== end {} ===", self.key));
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Sampling
+
+/// Only log 1 out of every `rate` messages of the given severity.
+/// 
+/// The number of dropped messages is appended to the next logged message
+/// of that severity, as `(skipped N similar messages)`. A rate of 1 logs
+/// every message, which is the default.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, Severity};
+/// use std::num::NonZeroU32;
+/// 
+/// mhlog::set_sample_rate(Severity::Info, NonZeroU32::new(100).unwrap());
+/// for i in 0..1000 {
+///     info!("Packet {}", i);
+/// }
+/// ```
+pub fn set_sample_rate(severity: Severity, rate: std::num::NonZeroU32) {
+    SAMPLE_RATES[severity.index()].store(rate.get(), Ordering::Relaxed);
+}
+
+/// Decide whether to log a message of the given severity. Returns the
+/// number of messages skipped since the last logged one, or `None` if
+/// this message should be skipped.
+fn sample(severity: Severity) -> Option<u64> {
+    let i = severity.index();
+    let rate = SAMPLE_RATES[i].load(Ordering::Relaxed) as u64;
+    if rate == 1 {
+        return Some(0)
+    }
+    if SAMPLE_COUNTS[i].fetch_add(1, Ordering::Relaxed) % rate != 0 {
+        SAMPLE_SKIPPED[i].fetch_add(1, Ordering::Relaxed);
+        return None
+    }
+    Some(SAMPLE_SKIPPED[i].swap(0, Ordering::Relaxed))
+}
+
+// -----------------------------------------------------------------------------
+// Level
+
+/// The most detailed severity shown. See [`set_level`].
+/// 
+/// [`set_level`]: fn.set_level.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// No messages are shown.
+    Off,
+    Err,
+    Warn,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ParseError;
+
+    /// Parse a log level name, case-insensitively. `"error"`, `"warning"`
+    /// and `"trace"` are accepted as aliases of `"err"`, `"warn"` and
+    /// `"debug"`.
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "err" | "error" => Ok(LogLevel::Err),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "verbose" => Ok(LogLevel::Verbose),
+            "debug" | "trace" => Ok(LogLevel::Debug),
+            _ => Err(ParseError(s.to_string())),
+        }
+    }
+}
+
+/// Error returned when parsing an invalid log level name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError(String);
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid log level: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Show only messages with severity `level` or more severe.
+/// 
+/// Errors, warnings and info messages below the level are suppressed, and
+/// verbose and debug messages are enabled/disabled as with [`set_verbose`]
+/// and [`set_debug`].
+/// 
+/// [`set_verbose`]: fn.set_verbose.html
+/// [`set_debug`]: fn.set_debug.html
+pub fn set_level(level: LogLevel) {
+    use Severity::*;
+
+    // Number of severities, in order from `Err`, which are shown.
+    let shown = level as usize;
+    {
+        let mut sup = SUPPRESSED.write().unwrap();
+        for sev in &[Err, Warn, Info] {
+            sup[sev.index()] = sev.index() >= shown;
+        }
+    }
+    set_verbose(shown > Verbose.index());
+    set_debug(shown > Debug.index());
+}
+
+/// Set the log level from its name, as read from e.g. an environment
+/// variable or command line argument. See [`set_level`].
+/// 
+/// Recognizes `"off"`, `"err"`, `"error"`, `"warn"`, `"warning"`, `"info"`,
+/// `"verbose"`, `"debug"`, and `"trace"`, case-insensitively.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// mhlog::set_level_from_str("Warning").unwrap();
+/// assert!(mhlog::set_level_from_str("loud").is_err());
+/// ```
+/// 
+/// [`set_level`]: fn.set_level.html
+pub fn set_level_from_str(s: &str) -> Result<(), ParseError> {
+    set_level(s.parse()?);
+    Ok(())
+}
+
+/// Show only messages of severity `min` or more severe until the returned
+/// guard is dropped, then restore the previous suppression, verbose and
+/// debug settings.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{err, info, Severity};
+/// {
+///     let _guard = mhlog::level_guard(Severity::Err);
+///     info!("Not shown");
+///     err!("Shown");
+/// }
+/// info!("Shown again");
+/// ```
+pub fn level_guard(min: Severity) -> LevelGuard {
+    let guard = LevelGuard::save();
+    set_level(match min {
+        Severity::Err => LogLevel::Err,
+        Severity::Warn => LogLevel::Warn,
+        Severity::Info => LogLevel::Info,
+        Severity::Verbose => LogLevel::Verbose,
+        Severity::Debug => LogLevel::Debug,
+    });
+    guard
+}
+
+/// Restores the level settings when dropped. See [`level_guard`].
+/// 
+/// [`level_guard`]: fn.level_guard.html
+#[must_use = "the level is restored when the guard is dropped"]
+#[derive(Debug)]
+pub struct LevelGuard {
+    suppressed: [bool; 5],
+    verbose: u32,
+    debug: bool,
+}
+
+impl LevelGuard {
+    fn save() -> Self {
+        LevelGuard {
+            suppressed: *SUPPRESSED.read().unwrap(),
+            verbose: verbose_level(),
+            debug: *DEBUG.read().unwrap(),
+        }
+    }
+}
+
+impl Drop for LevelGuard {
+    fn drop(&mut self) {
+        if let Ok(mut sup) = SUPPRESSED.write() {
+            *sup = self.suppressed;
+        }
+        set_verbose_level(self.verbose);
+        set_debug(self.debug);
+    }
+}
+
+/// Read the log level from the environment variable `var` while running,
+/// so the level can be changed without restarting, e.g. by a supervisor.
+/// 
+/// While the variable is set to a valid level name (see
+/// [`set_level_from_str`]) it decides which messages are shown, instead of
+/// the suppression, quiet period, verbose and debug settings. Otherwise
+/// those settings apply as usual. Quiet mode still applies.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{LogLevel, Severity};
+/// mhlog::set_level(LogLevel::Warn);
+/// std::env::set_var("MYAPP_LOG", "debug");
+/// mhlog::set_level_env_var("MYAPP_LOG");
+/// assert!(!Severity::Info.suppressed());
+/// assert!(!Severity::Debug.suppressed());
+/// 
+/// mhlog::clear_level_env_var();
+/// assert!(Severity::Info.suppressed());
+/// assert!(!Severity::Warn.suppressed());
+/// ```
+/// 
+/// A changed value is picked up within about a second: the variable is
+/// read at most once per second on each thread. This still makes every
+/// check of whether a message is suppressed a little more expensive, and
+/// the first check on each thread reads the environment. If the level is
+/// only given at startup, read it once with [`set_level_from_str`] instead.
+/// 
+/// [`set_level_from_str`]: fn.set_level_from_str.html
+pub fn set_level_env_var(var: &'static str) {
+    let mut v = LEVEL_ENV_VAR.write().unwrap();
+    *v = Some(var);
+}
+
+/// Stop reading the log level from an environment variable. See
+/// [`set_level_env_var`].
+/// 
+/// [`set_level_env_var`]: fn.set_level_env_var.html
+pub fn clear_level_env_var() {
+    let mut v = LEVEL_ENV_VAR.write().unwrap();
+    *v = None;
+}
+
+/// The level from the level environment variable, if one is set and valid,
+/// cached for a second per thread.
+fn env_level() -> Option<LogLevel> {
+    let var = (*LEVEL_ENV_VAR.read().unwrap())?;
+    ENV_LEVEL.with(|cache| {
+        let now = std::time::Instant::now();
+        match cache.get() {
+            Some((read, name, level)) if name == var && now - read < Duration::from_secs(1) => level,
+            _ => {
+                let level = std::env::var(var).ok().and_then(|s| s.parse().ok());
+                cache.set(Some((now, var, level)));
+                level
+            }
+        }
+    })
+}
+
+// -----------------------------------------------------------------------------
+// Config file
+
+/// Error returned by [`load_config`].
+/// 
+/// [`load_config`]: fn.load_config.html
+#[cfg(feature = "config-toml")]
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Io(std::io::Error),
+    /// The config file is not valid.
+    Parse(toml::de::Error),
+    /// The log level is not recognized.
+    InvalidLevel(String),
+}
+
+#[cfg(feature = "config-toml")]
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "failed to parse config: {}", e),
+            ConfigError::InvalidLevel(s) => write!(f, "invalid log level: {:?}", s),
+        }
+    }
+}
+
+#[cfg(feature = "config-toml")]
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::InvalidLevel(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "config-toml")]
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+#[cfg(feature = "config-toml")]
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+#[cfg(feature = "config-toml")]
+#[derive(serde::Deserialize)]
+struct ConfigFile {
+    log: Option<LogConfig>,
+}
+
+#[cfg(feature = "config-toml")]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogConfig {
+    level: Option<String>,
+    verbose: Option<bool>,
+    debug: Option<bool>,
+    prefix_err: Option<String>,
+    prefix_warn: Option<String>,
+    prefix_info: Option<String>,
+    prefix_debug: Option<String>,
+}
+
+/// Load configuration from a TOML file. Requires the `config-toml` feature.
+/// 
+/// All keys are optional, and are applied in the order listed below, so
+/// `verbose` and `debug` override what is implied by `level`:
+/// 
+/// ```toml
+/// [log]
+/// level = "info"  # off, err, warn, info, verbose or debug
+/// verbose = true
+/// debug = false
+/// prefix_err = "[ERROR]"
+/// prefix_warn = "[WARN]"
+/// prefix_info = "[INFO]"
+/// prefix_debug = "[DEBUG]"
+/// ```
+/// 
+/// Other sections of the file, like the rest of an application's
+/// configuration, are ignored, while unknown keys in the `[log]` section are
+/// an error:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "config-toml")] {
+/// let path = std::env::temp_dir().join("mhlog-load-config-doctest.toml");
+/// std::fs::write(&path, "[server]\nport = 8080\n\n[log]\nlevel = \"warn\"\n").unwrap();
+/// mhlog::load_config(&path).unwrap();
+/// 
+/// std::fs::write(&path, "[log]\nlevle = \"warn\"\n").unwrap();
+/// assert!(mhlog::load_config(&path).is_err());
+/// # std::fs::remove_file(&path).unwrap();
+/// # }
+/// ```
+/// 
+/// Nothing is applied if the file can't be read or parsed.
+#[cfg(feature = "config-toml")]
+pub fn load_config<P: AsRef<std::path::Path>>(path: P) -> Result<(), ConfigError> {
+    let txt = std::fs::read_to_string(path)?;
+    let cfg: ConfigFile = toml::from_str(&txt)?;
+    let log = match cfg.log {
+        Some(log) => log,
+        None => return Ok(()),
+    };
+
+    if let Some(level) = log.level {
+        if set_level_from_str(&level).is_err() {
+            return Err(ConfigError::InvalidLevel(level))
+        }
+    }
+    if let Some(v) = log.verbose {
+        set_verbose(v);
+    }
+    if let Some(v) = log.debug {
+        set_debug(v);
+    }
+    if let Some(s) = log.prefix_err {
+        error_prefix_str(s);
+    }
+    if let Some(s) = log.prefix_warn {
+        warning_prefix_str(s);
+    }
+    if let Some(s) = log.prefix_info {
+        info_prefix_str(s);
+    }
+    if let Some(s) = log.prefix_debug {
+        debug_prefix_str(s);
+    }
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Command line
+
+/// Register the standard `--verbose`, `--debug` and `--quiet` flags on a
+/// `clap` command. Requires the `clap-compat` feature.
+/// 
+/// Use [`configure_from_clap`] to apply the flags after parsing.
+/// 
+/// [`configure_from_clap`]: fn.configure_from_clap.html
+#[cfg(feature = "clap-compat")]
+pub fn add_mhlog_args(cmd: clap::Command) -> clap::Command {
+    use clap::{Arg, ArgAction};
+
+    cmd.arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .action(ArgAction::SetTrue)
+            .help("Print verbose messages"))
+        .arg(Arg::new("debug")
+            .long("debug")
+            .action(ArgAction::SetTrue)
+            .help("Print debug messages"))
+        .arg(Arg::new("quiet")
+            .short('q')
+            .long("quiet")
+            .action(ArgAction::SetTrue)
+            .help("Only print warnings and errors"))
+}
+
+/// Configure mhlog from the `verbose`, `debug` and `quiet` flags of parsed
+/// `clap` arguments. Requires the `clap-compat` feature.
+/// 
+/// Flags which are not defined are ignored. If `quiet` is set it takes
+/// priority, and only warnings and errors are shown.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "clap-compat")] {
+/// let cmd = mhlog::add_mhlog_args(clap::Command::new("app"));
+/// let matches = cmd.get_matches_from(["app", "--verbose"]);
+/// mhlog::configure_from_clap(&matches);
+/// # }
+/// ```
+#[cfg(feature = "clap-compat")]
+pub fn configure_from_clap(matches: &clap::ArgMatches) {
+    let flag = |name: &str| {
+        matches.try_get_one::<bool>(name).ok().flatten().copied().unwrap_or(false)
+    };
+    if flag("verbose") {
+        set_verbose(true);
+    }
+    if flag("debug") {
+        set_debug(true);
+    }
+    if flag("quiet") {
+        set_quiet(true);
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Bail
+
+/// Error returned by [`bail_result!()`], holding the logged message.
+/// 
+/// [`bail_result!()`]: macro.bail_result.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BailError(String);
+
+impl BailError {
+    pub fn new<S: Into<String>>(msg: S) -> Self {
+        BailError(msg.into())
+    }
+
+    /// The logged message.
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for BailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for BailError {}
+
+/// Error returned by [`ensure!()`], holding the logged message.
+/// 
+/// [`ensure!()`]: macro.ensure.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnsureError(String);
+
+impl EnsureError {
+    pub fn new<S: Into<String>>(msg: S) -> Self {
+        EnsureError(msg.into())
+    }
+
+    /// The logged message.
+    pub fn message(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for EnsureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EnsureError {}
+
+// -----------------------------------------------------------------------------
+// Panics
+
+/// Install a panic hook which logs panics with [`err!()`], and then calls
+/// the previous panic hook.
+/// 
+/// For the default hook the previous hook prints the panic message (again)
+/// and an optional backtrace. Use [`PanicHookBuilder`] to only log panics.
+/// The previous hook can be restored with [`remove_panic_hook`].
+/// 
+/// [`err!()`]: macro.err.html
+/// [`PanicHookBuilder`]: struct.PanicHookBuilder.html
+/// [`remove_panic_hook`]: fn.remove_panic_hook.html
+pub fn install_panic_hook() {
+    PanicHookBuilder::new().install();
+}
+
+/// Configure and install the panic hook of [`install_panic_hook`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// mhlog::PanicHookBuilder::new()
+///     .chain(false)
+///     .install();
+/// # mhlog::remove_panic_hook();
+/// ```
+/// 
+/// [`install_panic_hook`]: fn.install_panic_hook.html
+#[derive(Debug, Clone)]
+pub struct PanicHookBuilder {
+    chain: bool,
+}
+
+impl Default for PanicHookBuilder {
+    fn default() -> Self {
+        PanicHookBuilder { chain: true }
+    }
+}
+
+impl PanicHookBuilder {
+    pub fn new() -> Self {
+        PanicHookBuilder::default()
+    }
+
+    /// Whether to call the previous panic hook after logging. Enabled by
+    /// default.
+    pub fn chain(mut self, chain: bool) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Install the panic hook, replacing any installed by mhlog before.
+    pub fn install(self) {
+        let chain = self.chain;
+        let mut prev_hook = PREV_PANIC_HOOK.lock().unwrap();
+        let prev: PanicHook = match prev_hook.take() {
+            // Already installed, keep the original hook.
+            Some(prev) => prev,
+            None => Arc::from(std::panic::take_hook()),
+        };
+        *prev_hook = Some(Arc::clone(&prev));
+
+        std::panic::set_hook(Box::new(move |info| {
+            _log(Severity::Err, info.to_string());
+            write_barrier();
+            if chain {
+                prev(info);
+            }
+        }));
+    }
+}
+
+/// Remove the panic hook installed by [`install_panic_hook`], restoring the
+/// previous hook.
+/// 
+/// Does nothing if the hook is not installed.
+/// 
+/// [`install_panic_hook`]: fn.install_panic_hook.html
+pub fn remove_panic_hook() {
+    if let Some(prev) = PREV_PANIC_HOOK.lock().unwrap().take() {
+        std::panic::set_hook(Box::new(move |info| prev(info)));
+    }
+}
+
+/// Install a SIGINT/SIGTERM/SIGHUP handler which flushes buffered log
+/// messages with [`write_barrier`] before the process exits. Requires the
+/// `signal-handler` feature.
+/// 
+/// On Unix the signal is raised again with its default action after
+/// flushing, so the process dies by the signal, as seen by its parent. On
+/// Windows the handler is registered with `SetConsoleCtrlHandler`, and the
+/// process exits with code 130.
+/// 
+/// Returns an error if a handler is already installed, by this function or
+/// by other users of the `ctrlc` crate.
+/// 
+/// [`write_barrier`]: fn.write_barrier.html
+#[cfg(feature = "signal-handler")]
+pub fn install_ctrlc_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        write_barrier();
+        #[cfg(unix)]
+        unsafe {
+            let sig = match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+                0 => libc::SIGINT,
+                sig => sig,
+            };
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+        std::process::exit(130);
+    })?;
+    #[cfg(unix)]
+    record_signals();
+    Ok(())
+}
+
+/// The last signal received by the handler of `install_ctrlc_handler`.
+#[cfg(all(feature = "signal-handler", unix))]
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// The handlers installed by `ctrlc` for the `HANDLED_SIGNALS`.
+#[cfg(all(feature = "signal-handler", unix))]
+static CTRLC_HANDLERS: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Whether the `CTRLC_HANDLERS` were installed with `SA_SIGINFO`, taking
+/// three arguments instead of one.
+#[cfg(all(feature = "signal-handler", unix))]
+static CTRLC_SIGINFO: [AtomicBool; 3] = [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)];
+
+#[cfg(all(feature = "signal-handler", unix))]
+const HANDLED_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Wrap the signal handlers of `ctrlc`, which don't tell which signal was
+/// received, with one recording the signal before calling them.
+#[cfg(all(feature = "signal-handler", unix))]
+fn record_signals() {
+    extern "C" fn record(sig: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+        RECEIVED_SIGNAL.store(sig, Ordering::SeqCst);
+        if let Some(i) = HANDLED_SIGNALS.iter().position(|&s| s == sig) {
+            let handler = CTRLC_HANDLERS[i].load(Ordering::SeqCst);
+            if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+                return
+            }
+            // The handler is a function pointer stored as `sa_sigaction`,
+            // and the kernel calls it with three arguments if `SA_SIGINFO`
+            // was set and one otherwise. Calling it with the same signature
+            // as it was installed with is what makes the transmute sound.
+            if CTRLC_SIGINFO[i].load(Ordering::SeqCst) {
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    unsafe { std::mem::transmute(handler) };
+                handler(sig, info, ctx);
+            } else {
+                let handler: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(handler) };
+                handler(sig);
+            }
+        }
+    }
+
+    for (i, &sig) in HANDLED_SIGNALS.iter().enumerate() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(sig, std::ptr::null(), &mut action) != 0 {
+                continue
+            }
+            CTRLC_HANDLERS[i].store(action.sa_sigaction, Ordering::SeqCst);
+            CTRLC_SIGINFO[i].store(action.sa_flags & libc::SA_SIGINFO != 0, Ordering::SeqCst);
+            // Keep the mask and the other flags of the wrapped handler, but
+            // always take the three argument form so `info` can be passed on.
+            action.sa_sigaction = record as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) as libc::sighandler_t;
+            action.sa_flags |= libc::SA_SIGINFO;
+            libc::sigaction(sig, &action, std::ptr::null_mut());
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Logger
+
+/// A logger with its own configuration, independent of the global one.
+/// 
+/// Useful for library crates, which should not change the global
+/// configuration of the application using them. Pass the logger as
+/// `logger: <logger>` before the format string to any of the logging
+/// macros:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, debug, Severity};
+/// let mut logger = mhlog::Logger::new();
+/// logger.set_debug(true);
+/// logger.set_prefix_str(Severity::Info, "[mylib]".to_string());
+/// info!(logger: logger, "Hello from a library");
+/// debug!(logger: logger, "Debug is enabled for this logger only");
+/// ```
+/// 
+/// Clones of a logger share the same writer.
+#[derive(Clone)]
+pub struct Logger {
+    prefixes: LogPrefixes,
+    verbose: bool,
+    debug: bool,
+    writer: Option<Arc<Mutex<Box<dyn std::io::Write + Send + Sync>>>>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger {
+            prefixes: LogPrefixes::new(),
+            verbose: false,
+            debug: false,
+            writer: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Logger {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Logger")
+            .field("prefixes", &self.prefixes)
+            .field("verbose", &self.verbose)
+            .field("debug", &self.debug)
+            .field("writer", &self.writer.is_some())
+            .finish()
+    }
+}
+
+impl Logger {
+    /// Create a logger with the default configuration, writing to
+    /// stdout/stderr.
+    pub fn new() -> Self {
+        Logger::default()
+    }
+
+    /// Create a logger writing all messages to `writer`.
+    pub fn with_writer(writer: Box<dyn std::io::Write + Send + Sync>) -> Self {
+        Logger {
+            writer: Some(Arc::new(Mutex::new(writer))),
+            ..Logger::default()
+        }
+    }
+
+    /// Enable/disable verbose messages for this logger.
+    pub fn set_verbose(&mut self, val: bool) {
+        self.verbose = val;
+    }
+
+    /// Enable/disable debug messages for this logger.
+    pub fn set_debug(&mut self, val: bool) {
+        self.debug = val;
+    }
+
+    /// Change the prefix of a severity to a new static value.
+    pub fn set_prefix_str(&mut self, severity: Severity, s: String) {
+        *self.prefixes.slots(severity).1 = s;
+    }
+
+    /// Change the prefix of a severity to a dynamic value.
+    pub fn set_prefix_fn(&mut self, severity: Severity, f: PrefixFn) {
+        *self.prefixes.slots(severity).0 = Some(f);
+    }
+
+    /// Log a message with the given severity.
+    pub fn log<M: std::fmt::Display>(&self, severity: Severity, msg: M) {
+        use std::io::Write;
+        use Severity::*;
+
+        let suppressed = match severity {
+            Debug => !self.debug,
+            Verbose => !self.verbose,
+            _ => false,
+        };
+        if suppressed {
+            return
+        }
+
+        let prefix = self.prefixes.get(severity);
+        let txt = format_plain(&prefix, &msg.to_string());
+        if self.writer.is_some() && NULL_OUTPUT.load(Ordering::Relaxed) {
+            return
+        }
+        match &self.writer {
+            Some(w) => {
+                let _ = w.lock().unwrap().write_all(&encode(&txt));
+            }
+            None => write_out(severity.to_stderr(), &encode(&txt)),
+        }
+    }
+
+    pub fn err<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Err, msg);
+    }
+
+    pub fn warn<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Warn, msg);
+    }
+
+    pub fn info<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Info, msg);
+    }
+
+    pub fn verbose<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Verbose, msg);
+    }
+
+    pub fn debug<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Debug, msg);
+    }
+}
+
+/// A logger which only evaluates the format string of messages, for
+/// measuring the cost of the messages themselves in benchmarks.
+/// 
+/// Messages are formatted and discarded, without computing any prefix or
+/// checking whether the severity is suppressed. See also
+/// [`set_null_output`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// let bench = mhlog::BenchmarkLogger::new();
+/// bench.info(format_args!("Iteration {}", 1));
+/// ```
+/// 
+/// [`set_null_output`]: fn.set_null_output.html
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BenchmarkLogger;
+
+impl BenchmarkLogger {
+    pub fn new() -> Self {
+        BenchmarkLogger
+    }
+
+    /// Format a message with the given severity, and discard it.
+    pub fn log<M: std::fmt::Display>(&self, _severity: Severity, msg: M) {
+        use std::io::Write;
+        let _ = write!(std::io::sink(), "{}", msg);
+    }
+
+    pub fn err<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Err, msg);
+    }
+
+    pub fn warn<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Warn, msg);
+    }
+
+    pub fn info<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Info, msg);
+    }
+
+    pub fn verbose<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Verbose, msg);
+    }
+
+    pub fn debug<M: std::fmt::Display>(&self, msg: M) {
+        self.log(Severity::Debug, msg);
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Targets
+
+/// Route messages logged with the target `name`, like
+/// `info!(target: "http", ...)`, to `logger`. Replaces any logger previously
+/// registered for the target.
+/// 
+/// Messages with a target which has no registered logger are logged as
+/// usual, with the target added to the prefix:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::info;
+/// info!(target: "http", "Request received"); // Prints: [*][http] Request received
+/// 
+/// mhlog::register_target("db", mhlog::Logger::with_writer(Box::new(std::io::sink())));
+/// info!(target: "db", "Query done");         // Prints nothing
+/// mhlog::unregister_target("db");
+/// ```
+pub fn register_target<S: Into<String>>(name: S, logger: Logger) {
+    TARGETS.write().unwrap().insert(name.into(), logger);
+}
+
+/// Remove the logger registered for a target with [`register_target`].
+/// 
+/// [`register_target`]: fn.register_target.html
+pub fn unregister_target(name: &str) {
+    TARGETS.write().unwrap().remove(name);
+}
+
+// -----------------------------------------------------------------------------
+// Syslog
+
+/// Destination of syslog messages set with `use_udp_syslog`.
+#[cfg(feature = "udp-syslog")]
+struct UdpSyslog {
+    socket: std::net::UdpSocket,
+    hostname: String,
+    app_name: String,
+}
+
+/// Send every log message to a syslog collector over UDP, formatted as
+/// RFC 5424, in addition to the usual output. Requires the `udp-syslog`
+/// feature.
+/// 
+/// Messages use the facility `LOCAL0`. Errors are sent as `err`, warnings
+/// as `warning`, info and verbose messages as `info`, and debug messages as
+/// `debug`. Failures to send are ignored.
+/// 
+/// Only printable ASCII is allowed in the hostname and app name, so other
+/// characters (including spaces) are removed, and they are truncated to 255
+/// and 48 characters respectively. An empty value is sent as `-`.
+/// 
+/// ```rust,no_run
+/// # extern crate mhlog;
+/// # #[cfg(feature = "udp-syslog")]
+/// mhlog::use_udp_syslog("127.0.0.1:514".parse().unwrap(), "myhost", "myapp").unwrap();
+/// ```
+/// 
+/// ```rust
+/// # #[macro_use] extern crate mhlog;
+/// # fn main() {
+/// # #[cfg(feature = "udp-syslog")] {
+/// let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// mhlog::use_udp_syslog(collector.local_addr().unwrap(), "my host", "").unwrap();
+/// info!("Hello");
+/// mhlog::stop_udp_syslog();
+/// 
+/// let mut buf = [0; 1024];
+/// let n = collector.recv(&mut buf).unwrap();
+/// let frame = String::from_utf8_lossy(&buf[..n]).into_owned();
+/// let fields: Vec<&str> = frame.split(' ').collect();
+/// assert_eq!(fields[2], "myhost");
+/// assert_eq!(fields[3], "-");
+/// # }
+/// # }
+/// ```
+#[cfg(feature = "udp-syslog")]
+pub fn use_udp_syslog(addr: std::net::SocketAddr, hostname: &str, app_name: &str) -> std::io::Result<()> {
+    let local: std::net::SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    *UDP_SYSLOG.write().unwrap() = Some(UdpSyslog {
+        socket,
+        hostname: syslog_field(hostname, 255),
+        app_name: syslog_field(app_name, 48),
+    });
+    Ok(())
+}
+
+/// Make `value` a valid RFC 5424 header field: printable ASCII only, at
+/// most `max` characters, and `-` (the nil value) if empty.
+#[cfg(feature = "udp-syslog")]
+fn syslog_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+/// Stop sending log messages to syslog. See [`use_udp_syslog`].
+/// 
+/// [`use_udp_syslog`]: fn.use_udp_syslog.html
+#[cfg(feature = "udp-syslog")]
+pub fn stop_udp_syslog() {
+    *UDP_SYSLOG.write().unwrap() = None;
+}
+
+#[cfg(feature = "udp-syslog")]
+fn syslog_send(severity: Severity, msg: &str) {
+    const LOCAL0: u8 = 16;
+
+    let syslog = UDP_SYSLOG.read().unwrap();
+    let syslog = match &*syslog {
+        Some(syslog) => syslog,
+        None => return,
+    };
+    let level = match severity {
+        Severity::Err => 3,
+        Severity::Warn => 4,
+        Severity::Info | Severity::Verbose => 6,
+        Severity::Debug => 7,
+    };
+    let frame = format!(
+        "<{}>1 {} {} {} {} - - {}",
+        LOCAL0 * 8 + level, utc_now(), syslog.hostname, syslog.app_name, std::process::id(), msg,
+    );
+    let _ = syslog.socket.send(frame.as_bytes());
+}
+
+// -----------------------------------------------------------------------------
+// Records
+
+/// A source code location, like `src/main.rs:42`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+impl std::fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// A log message with its metadata, which may be constructed offline (e.g.
+/// from a network message or a parsed log file) and replayed with
+/// [`log_record`].
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{LogRecord, Severity};
+/// let record = LogRecord::builder()
+///     .severity(Severity::Warn)
+///     .message("Disk almost full")
+///     .location("src/disk.rs", 42)
+///     .build();
+/// mhlog::log_record(&record); // Prints: [!] Disk almost full at=src/disk.rs:42
+/// ```
+/// 
+/// [`log_record`]: fn.log_record.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub severity: Severity,
+    pub message: String,
+    pub timestamp: std::time::SystemTime,
+    pub thread_id: std::thread::ThreadId,
+    pub location: Option<SourceLocation>,
+}
+
+impl LogRecord {
+    /// Start building a record. By default it is an empty info message,
+    /// from the current thread at the current time, without location.
+    pub fn builder() -> LogRecordBuilder {
+        LogRecordBuilder {
+            record: LogRecord {
+                severity: Severity::Info,
+                message: String::new(),
+                timestamp: std::time::SystemTime::now(),
+                thread_id: std::thread::current().id(),
+                location: None,
+            },
+        }
+    }
+}
+
+/// Builder of a [`LogRecord`], see [`LogRecord::builder`].
+/// 
+/// [`LogRecord`]: struct.LogRecord.html
+/// [`LogRecord::builder`]: struct.LogRecord.html#method.builder
+#[derive(Debug, Clone)]
+pub struct LogRecordBuilder {
+    record: LogRecord,
+}
+
+impl LogRecordBuilder {
+    pub fn severity(mut self, severity: Severity) -> Self {
+        self.record.severity = severity;
+        self
+    }
+
+    pub fn message<S: Into<String>>(mut self, msg: S) -> Self {
+        self.record.message = msg.into();
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: std::time::SystemTime) -> Self {
+        self.record.timestamp = timestamp;
+        self
+    }
+
+    pub fn thread_id(mut self, id: std::thread::ThreadId) -> Self {
+        self.record.thread_id = id;
+        self
+    }
+
+    pub fn location<S: Into<String>>(mut self, file: S, line: u32) -> Self {
+        self.record.location = Some(SourceLocation { file: file.into(), line });
+        self
+    }
+
+    pub fn build(self) -> LogRecord {
+        self.record
+    }
+}
+
+/// Log a record through the usual output machinery, as if its message was
+/// logged with its severity: redirects, suppression, interceptors,
+/// prefixes and formatting all apply.
+/// 
+/// The location, if any, is added as the field `at`. The timestamp and
+/// thread ID are not written, but kept for external processing of the
+/// record.
+pub fn log_record(record: &LogRecord) {
+    match &record.location {
+        Some(loc) => {
+            let at = loc.to_string();
+            _log_fields(record.severity, record.message.clone(), &[("at", &at)]);
+        }
+        None => _log(record.severity, record.message.clone()),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// log crate bridge
+
+/// Forwards the records of the `log` crate to mhlog.
+#[cfg(feature = "log-bridge")]
+struct MhlogLogger;
+
+#[cfg(feature = "log-bridge")]
+impl MhlogLogger {
+    fn severity(level: log::Level) -> Severity {
+        match level {
+            log::Level::Error => Severity::Err,
+            log::Level::Warn => Severity::Warn,
+            log::Level::Info => Severity::Info,
+            log::Level::Debug | log::Level::Trace => Severity::Debug,
+        }
+    }
+}
+
+#[cfg(feature = "log-bridge")]
+impl log::Log for MhlogLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        !MhlogLogger::severity(metadata.level()).redirected().suppressed()
+    }
+
+    fn log(&self, record: &log::Record) {
+        _log(MhlogLogger::severity(record.level()), *record.args());
+    }
+
+    fn flush(&self) {
+        write_barrier();
+    }
+}
+
+/// Install mhlog as the logger of the `log` crate, so messages from
+/// `log::info!()` etc. are written by mhlog. Requires the `log-bridge`
+/// feature.
+/// 
+/// Errors and warnings keep their severity, info messages are logged as
+/// info, and debug and trace messages as debug. The max level of the `log`
+/// crate is set to `Trace`, leaving all filtering to mhlog.
+/// 
+/// The `log` crate only allows its logger to be set once per process, so
+/// this fails if it is called twice, or if another logger has already been
+/// installed (e.g. by `env_logger`).
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "log-bridge")] {
+/// mhlog::install_log_crate_bridge().unwrap();
+/// log::warn!("Forwarded to mhlog");
+/// assert!(mhlog::install_log_crate_bridge().is_err());
+/// # }
+/// ```
+#[cfg(feature = "log-bridge")]
+pub fn install_log_crate_bridge() -> Result<(), log::SetLoggerError> {
+    log::set_boxed_logger(Box::new(MhlogLogger))?;
+    log::set_max_level(log::LevelFilter::Trace);
+    Ok(())
+}
+
+// -----------------------------------------------------------------------------
+// Metrics
+
+/// Number of messages of a severity logged since process start or the last
+/// [`reset_counts`]. Requires the `metrics` feature.
+/// 
+/// Suppressed, sampled out and intercepted messages are not counted.
+/// 
+/// [`reset_counts`]: fn.reset_counts.html
+#[cfg(feature = "metrics")]
+pub fn message_count(severity: Severity) -> u64 {
+    MESSAGE_COUNTS[severity.index()].load(Ordering::Relaxed)
+}
+
+/// Reset all message counts to zero, and restart the time used for the
+/// rates of [`stats_summary`]. Requires the `metrics` feature.
+/// 
+/// [`stats_summary`]: fn.stats_summary.html
+#[cfg(feature = "metrics")]
+pub fn reset_counts() {
+    let mut reset = COUNTS_RESET.write().unwrap();
+    for count in &MESSAGE_COUNTS {
+        count.store(0, Ordering::Relaxed);
+    }
+    *reset = std::time::Instant::now();
+}
+
+/// A human-readable table of the number of messages logged of each
+/// severity, and their rate per second since the first message or the last
+/// [`reset_counts`]. Requires the `metrics` feature.
+/// 
+/// ```text
+/// Level   | Count | Rate/s
+/// error   | 12    | 0.02
+/// warn    | 347   | 0.58
+/// info    | 9812  | 16.35
+/// verbose | 0     | 0.00
+/// debug   | 0     | 0.00
+/// ```
+/// 
+/// [`reset_counts`]: fn.reset_counts.html
+#[cfg(feature = "metrics")]
+pub fn stats_summary() -> String {
+    use std::fmt::Write;
+    use Severity::*;
+
+    let secs = COUNTS_RESET.read().unwrap().elapsed().as_secs_f64();
+    let mut s = String::from("Level   | Count | Rate/s\n");
+    for (sev, name) in &[(Err, "error"), (Warn, "warn"), (Info, "info"), (Verbose, "verbose"), (Debug, "debug")] {
+        let n = message_count(*sev);
+        let rate = if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let _ = writeln!(s, "{:<7} | {:<5} | {:.2}", name, n, rate);
+    }
+    s
+}
+
+// -----------------------------------------------------------------------------
+// Test utilities
+
+/// Reentrant lock which serializes the test utilities, so they can be used
+/// from tests running in parallel, and nested within each other.
+#[cfg(feature = "test-utils")]
+#[derive(Default)]
+struct TestLock {
+    /// The thread holding the lock, and how many times it holds it.
+    state: Mutex<(Option<std::thread::ThreadId>, usize)>,
+    released: std::sync::Condvar,
+}
+
+/// Releases the test lock once when dropped, on the thread which took it.
+#[cfg(feature = "test-utils")]
+struct TestLockGuard {
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+#[cfg(feature = "test-utils")]
+impl Drop for TestLockGuard {
+    fn drop(&mut self) {
+        let mut state = TEST_LOCK.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.1 -= 1;
+        if state.1 == 0 {
+            state.0 = None;
+            TEST_LOCK.released.notify_all();
+        }
+    }
+}
+
+/// Take the test lock, waiting while another thread holds it. Not poisoned
+/// by panicking tests.
+#[cfg(feature = "test-utils")]
+fn test_lock() -> TestLockGuard {
+    let me = std::thread::current().id();
+    let mut state = TEST_LOCK.state.lock().unwrap_or_else(|e| e.into_inner());
+    while matches!(state.0, Some(owner) if owner != me) {
+        state = TEST_LOCK.released.wait(state).unwrap_or_else(|e| e.into_inner());
+    }
+    state.0 = Some(me);
+    state.1 += 1;
+    TestLockGuard { _not_send: std::marker::PhantomData }
+}
+
+/// Count the messages of the given severity logged while calling `f`.
+/// Requires the `test-utils` feature.
+/// 
+/// Only messages which are actually written are counted, not suppressed
+/// ones. Calls from parallel tests are serialized, but messages logged by
+/// other threads while `f` is running are also counted.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{warn, Severity};
+/// # #[cfg(feature = "test-utils")] {
+/// let n = mhlog::capture_count(Severity::Warn, || {
+///     warn!("One");
+///     warn!("Two");
+/// });
+/// assert_eq!(n, 2);
+/// # }
+/// ```
+#[cfg(feature = "test-utils")]
+pub fn capture_count<F: FnOnce()>(severity: Severity, f: F) -> u64 {
+    let _lock = test_lock();
+    let counter = &CAPTURE_COUNTS[severity.index()];
+    let before = counter.load(Ordering::SeqCst);
+    f();
+    counter.load(Ordering::SeqCst) - before
+}
+
+/// Count the errors logged by the current thread while calling `f`.
+/// Requires the `test-utils` feature.
+/// 
+/// Unlike [`capture_count`] this takes no lock and resets nothing: the
+/// per-thread count is read before and after calling `f`. Parallel tests
+/// therefore don't affect each other, but messages logged by other
+/// threads, including threads spawned by `f`, are not counted. Only
+/// messages which are actually written are counted, not suppressed ones.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{err, warn};
+/// # #[cfg(feature = "test-utils")] {
+/// let n = mhlog::with_err_count(|| {
+///     err!("Failed");
+///     warn!("Not an error");
+/// });
+/// assert_eq!(n, 1);
+/// # }
+/// ```
+/// 
+/// [`capture_count`]: fn.capture_count.html
+#[cfg(feature = "test-utils")]
+pub fn with_err_count<F: FnOnce()>(f: F) -> u64 {
+    thread_count(Severity::Err, f)
+}
+
+/// Count the warnings logged by the current thread while calling `f`.
+/// See [`with_err_count`].
+/// 
+/// [`with_err_count`]: fn.with_err_count.html
+#[cfg(feature = "test-utils")]
+pub fn with_warn_count<F: FnOnce()>(f: F) -> u64 {
+    thread_count(Severity::Warn, f)
+}
+
+#[cfg(feature = "test-utils")]
+fn thread_count<F: FnOnce()>(severity: Severity, f: F) -> u64 {
+    let count = || THREAD_COUNTS.with(|c| c.get()[severity.index()]);
+    let before = count();
+    f();
+    count() - before
+}
+
+/// Capture everything written to the file descriptor `fd` while calling
+/// `f`, by temporarily pointing it at a pipe.
+#[cfg(all(feature = "test-utils", unix))]
+fn capture_fd<F: FnOnce()>(fd: libc::c_int, f: F) -> String {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    struct Restore {
+        fd: libc::c_int,
+        saved: libc::c_int,
+    }
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            write_barrier();
+            unsafe {
+                libc::dup2(self.saved, self.fd);
+                libc::close(self.saved);
+            }
+        }
+    }
+
+    let _lock = test_lock();
+    write_barrier();
+    let mut fds = [0; 2];
+    let read_end = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            panic!("pipe failed: {}", std::io::Error::last_os_error());
+        }
+        std::fs::File::from_raw_fd(fds[0])
+    };
+    // Read concurrently, so `f` doesn't block on a full pipe.
+    let reader = std::thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = { read_end }.read_to_end(&mut out);
+        out
+    });
+    {
+        let saved = unsafe { libc::dup(fd) };
+        let _restore = Restore { fd, saved };
+        unsafe {
+            libc::dup2(fds[1], fd);
+            libc::close(fds[1]);
+        }
+        f();
+    }
+    let out = reader.join().unwrap_or_default();
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Capture everything written to stderr (file descriptor 2) while calling
+/// `f`, including output written directly to the file descriptor by C
+/// code. Requires the `test-utils` feature, and a Unix platform.
+/// 
+/// Captures from parallel tests are serialized, but output from other
+/// threads while `f` is running is also captured.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(all(feature = "test-utils", unix))] {
+/// # use mhlog::err;
+/// let out = mhlog::with_stderr_capture(|| err!("Failed"));
+/// assert!(out.contains("Failed"));
+/// # }
+/// ```
+#[cfg(all(feature = "test-utils", unix))]
+pub fn with_stderr_capture<F: FnOnce()>(f: F) -> String {
+    capture_fd(libc::STDERR_FILENO, f)
+}
+
+/// Capture everything written to stdout (file descriptor 1) while calling
+/// `f`. See [`with_stderr_capture`].
+/// 
+/// [`with_stderr_capture`]: fn.with_stderr_capture.html
+#[cfg(all(feature = "test-utils", unix))]
+pub fn with_stdout_capture<F: FnOnce()>(f: F) -> String {
+    capture_fd(libc::STDOUT_FILENO, f)
+}
+
+/// Records log messages instead of writing them, for use in unit tests.
+/// Requires the `test-utils` feature.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{warn, Severity};
+/// # #[cfg(feature = "test-utils")] {
+/// let mock = mhlog::MockLogger::install();
+/// warn!("Disk {} is almost full", "sda");
+/// mock.assert_contains(Severity::Warn, "almost full");
+/// assert_eq!(mock.records().len(), 1);
+/// # }
+/// ```
+#[cfg(feature = "test-utils")]
+#[derive(Debug, Default, Clone)]
+pub struct MockLogger {
+    records: Arc<Mutex<Vec<LogRecord>>>,
+}
+
+#[cfg(feature = "test-utils")]
+impl MockLogger {
+    pub fn new() -> Self {
+        MockLogger::default()
+    }
+
+    /// Install a new mock logger, which records all messages which are
+    /// not suppressed, until the returned guard is dropped.
+    /// 
+    /// Nothing is written to stdout/stderr while the mock logger is
+    /// installed. Installs from parallel tests are serialized. Installs may
+    /// be nested, in which case all the installed mock loggers record the
+    /// messages.
+    pub fn install() -> MockLoggerGuard {
+        let lock = test_lock();
+        let mock = MockLogger::new();
+        MOCKS.write().unwrap().push(mock.clone());
+        MockLoggerGuard { mock, _lock: lock }
+    }
+
+    /// The messages recorded so far, without prefix.
+    pub fn records(&self) -> Vec<(Severity, String)> {
+        self.records.lock().unwrap().iter()
+            .map(|r| (r.severity, r.message.clone()))
+            .collect()
+    }
+
+    /// The messages recorded so far, with the time and thread they were
+    /// logged from.
+    pub fn log_records(&self) -> Vec<LogRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+/// Uninstalls the [`MockLogger`] when dropped.
+/// 
+/// [`MockLogger`]: struct.MockLogger.html
+#[cfg(feature = "test-utils")]
+pub struct MockLoggerGuard {
+    mock: MockLogger,
+    _lock: TestLockGuard,
+}
+
+#[cfg(feature = "test-utils")]
+impl MockLoggerGuard {
+    /// The messages recorded so far, without prefix.
+    pub fn records(&self) -> Vec<(Severity, String)> {
+        self.mock.records()
+    }
+
+    /// Panic unless a message of the given severity containing `pattern`
+    /// has been recorded.
+    pub fn assert_contains(&self, severity: Severity, pattern: &str) {
+        let records = self.records();
+        if !records.iter().any(|(sev, msg)| *sev == severity && msg.contains(pattern)) {
+            panic!("no {:?} message containing {:?} was logged, got: {:?}", severity, pattern, records);
+        }
+    }
+}
+
+#[cfg(feature = "test-utils")]
+impl Drop for MockLoggerGuard {
+    fn drop(&mut self) {
+        if let Ok(mut mocks) = MOCKS.write() {
+            mocks.retain(|m| !Arc::ptr_eq(&m.records, &self.mock.records));
+        }
+    }
+}
+
+/// Call `f` with the log level set to `level` and a [`MockLogger`]
+/// installed, and return the messages it logged. Requires the
+/// `test-utils` feature.
+/// 
+/// The previous level is restored afterwards, also if `f` panics.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "test-utils")] {
+/// # use mhlog::{debug, LogLevel, Severity};
+/// let records = mhlog::with_level(LogLevel::Debug, || {
+///     debug!("Cache miss");
+/// });
+/// assert_eq!(records.len(), 1);
+/// assert_eq!(records[0].severity, Severity::Debug);
+/// assert_eq!(records[0].message, "Cache miss");
+/// # }
+/// ```
+/// 
+/// It may be nested with the other test utilities, which also see the
+/// messages:
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "test-utils")] {
+/// # use mhlog::{debug, info, LogLevel, Severity};
+/// let n = mhlog::capture_count(Severity::Info, || {
+///     let records = mhlog::with_level(LogLevel::Debug, || {
+///         info!("Starting");
+///         debug!("Cache miss");
+///     });
+///     assert_eq!(records.len(), 2);
+/// });
+/// assert_eq!(n, 1);
+/// # }
+/// ```
+/// 
+/// [`MockLogger`]: struct.MockLogger.html
+#[cfg(feature = "test-utils")]
+pub fn with_level<F: FnOnce()>(level: LogLevel, f: F) -> Vec<LogRecord> {
+    let mock = MockLogger::install();
+    let _guard = LevelGuard::save();
+    set_level(level);
+    f();
+    mock.mock.log_records()
+}
+
+/// Run `f` with a [`MockLogger`] installed, and panic with the recorded
+/// messages if any of the given severity were logged.
+/// 
+/// [`MockLogger`]: struct.MockLogger.html
+#[cfg(feature = "test-utils")]
+#[doc(hidden)]
+pub fn _assert_none_logged<T, F: FnOnce() -> T>(severity: Severity, f: F) -> T {
+    let mock = MockLogger::install();
+    let val = f();
+    let found: Vec<String> = mock.records().into_iter()
+        .filter(|(sev, _)| *sev == severity)
+        .map(|(_, msg)| msg)
+        .collect();
+    drop(mock);
+    if !found.is_empty() {
+        panic!("{} {:?} message(s) logged: {:?}", found.len(), severity, found);
+    }
+    val
+}
+
+/// Panic if any error is logged while evaluating the given expression,
+/// listing the logged errors. Evaluates to the value of the expression.
+/// Requires the `test-utils` feature.
+/// 
+/// A [`MockLogger`] is installed while the expression is evaluated, so
+/// nothing is written. It may be nested with the other test utilities.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "test-utils")] {
+/// # use mhlog::{assert_no_err, info};
+/// let n = assert_no_err!({
+///     info!("Just info");
+///     42
+/// });
+/// assert_eq!(n, 42);
+/// # }
+/// ```
+/// 
+/// [`MockLogger`]: struct.MockLogger.html
+#[cfg(feature = "test-utils")]
+#[macro_export]
+macro_rules! assert_no_err {
+    ($e:expr) => (
+        $crate::_assert_none_logged($crate::Severity::Err, || $e)
+    )
+}
+
+/// Panic if any warning is logged while evaluating the given expression.
+/// See [`assert_no_err!()`]
+/// 
+/// [`assert_no_err!()`]: macro.assert_no_err.html
+#[cfg(feature = "test-utils")]
+#[macro_export]
+macro_rules! assert_no_warn {
+    ($e:expr) => (
+        $crate::_assert_none_logged($crate::Severity::Warn, || $e)
+    )
+}
+
+// -----------------------------------------------------------------------------
+// Builder
+
+/// Configure multiple settings at once.
+/// 
+/// All changes are applied atomically by [`LogBuilder::build`], so other
+/// threads never observe a partially applied configuration.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// mhlog::LogBuilder::new()
+///     .verbose(true)
+///     .debug(false)
+///     .info_prefix("[INFO]")
+///     .build();
+/// ```
+/// 
+/// [`LogBuilder::build`]: struct.LogBuilder.html#method.build
+#[derive(Debug, Default)]
+pub struct LogBuilder {
+    verbose: Option<bool>,
+    debug: Option<bool>,
+    prefixes: Vec<(Severity, Option<PrefixFn>, Option<String>)>,
+}
+
+impl LogBuilder {
+    pub fn new() -> Self {
+        LogBuilder::default()
+    }
+
+    /// See [`set_verbose`](fn.set_verbose.html).
+    pub fn verbose(mut self, val: bool) -> Self {
+        self.verbose = Some(val);
+        self
+    }
+
+    /// See [`set_debug`](fn.set_debug.html).
+    pub fn debug(mut self, val: bool) -> Self {
+        self.debug = Some(val);
+        self
+    }
+
+    /// See [`error_prefix_str`](fn.error_prefix_str.html).
+    pub fn error_prefix<S: Into<String>>(self, s: S) -> Self {
+        self.prefix_str(Severity::Err, s.into())
+    }
+
+    /// See [`warning_prefix_str`](fn.warning_prefix_str.html).
+    pub fn warning_prefix<S: Into<String>>(self, s: S) -> Self {
+        self.prefix_str(Severity::Warn, s.into())
+    }
+
+    /// See [`info_prefix_str`](fn.info_prefix_str.html).
+    pub fn info_prefix<S: Into<String>>(self, s: S) -> Self {
+        self.prefix_str(Severity::Info, s.into())
+    }
+
+    /// See [`debug_prefix_str`](fn.debug_prefix_str.html).
+    pub fn debug_prefix<S: Into<String>>(self, s: S) -> Self {
+        self.prefix_str(Severity::Debug, s.into())
+    }
+
+    /// See [`error_prefix_fn`](fn.error_prefix_fn.html).
+    pub fn error_prefix_fn(self, f: PrefixFn) -> Self {
+        self.prefix_fn(Severity::Err, f)
+    }
+
+    /// See [`warning_prefix_fn`](fn.warning_prefix_fn.html).
+    pub fn warning_prefix_fn(self, f: PrefixFn) -> Self {
+        self.prefix_fn(Severity::Warn, f)
+    }
+
+    /// See [`info_prefix_fn`](fn.info_prefix_fn.html).
+    pub fn info_prefix_fn(self, f: PrefixFn) -> Self {
+        self.prefix_fn(Severity::Info, f)
+    }
+
+    /// See [`debug_prefix_fn`](fn.debug_prefix_fn.html).
+    pub fn debug_prefix_fn(self, f: PrefixFn) -> Self {
+        self.prefix_fn(Severity::Debug, f)
+    }
+
+    fn prefix_str(mut self, severity: Severity, s: String) -> Self {
+        self.prefixes.push((severity, None, Some(s)));
+        self
+    }
+
+    fn prefix_fn(mut self, severity: Severity, f: PrefixFn) -> Self {
+        self.prefixes.push((severity, Some(f), None));
+        self
+    }
+
+    /// Apply all the configured settings atomically.
+    pub fn build(self) {
+        let (new_verbose, new_debug) = {
+            let mut pre = prefixes_mut();
+            let mut verbose = VERBOSE_LEVEL.write().unwrap();
+            let mut debug = DEBUG.write().unwrap();
+
+            let new_verbose = self.verbose.filter(|&v| v != (*verbose > 0));
+            let new_debug = self.debug.filter(|&v| v != *debug);
+            if let Some(v) = self.verbose {
+                *verbose = v as u32;
+            }
+            if let Some(v) = self.debug {
+                *debug = v;
+            }
+            for (severity, f, s) in self.prefixes {
+                let (old_f, old_s) = pre.slots(severity);
+                if f.is_some() {
+                    *old_f = f;
+                }
+                if let Some(s) = s {
+                    *old_s = s;
+                }
+            }
+            (new_verbose, new_debug)
+        };
+
+        // Callbacks are called after the locks are released, so they may
+        // log or change the configuration.
+        if let Some(v) = new_verbose {
+            verbose_changed(v);
+        }
+        if let Some(v) = new_debug {
+            debug_changed(v);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Severity
+
+/// Log severity provides a common interface to all functionality which
+/// depends on the severity of a log message. Such as colouring, prefix,
+/// and stdout/stderr.
+/// 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Err,
+    Warn,
+    Info,
+    Verbose,
+    Debug,
+}
+
+impl Severity {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Whether messages of this severity should be coloured, according to
+    /// the colour mode.
+    #[cfg(feature = "colours")]
+    fn colors_enabled(self) -> bool {
+        match *COLOR_MODE.read().unwrap() {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto if self.to_stderr() => console::colors_enabled_stderr(),
+            ColorMode::Auto => console::colors_enabled(),
+        }
+    }
+
+    /// Name of the default colour of this severity: `"red"`, `"yellow"`,
+    /// `"normal"` or `"dim"`. Useful for custom colouring, like in a TUI.
+    /// 
+    /// This is the basic colour, also used by the `simple-colors` feature.
+    /// The `colours` feature styles verbose messages separately, see
+    /// [`set_verbose_style`](fn.set_verbose_style.html).
+    pub fn color_name(&self) -> &'static str {
+        use Severity::*;
+        match self {
+            Err => "red",
+            Warn => "yellow",
+            Info|Verbose => "normal",
+            Debug => "dim",
+        }
+    }
+
+    /// The basic ANSI escape code of the default colour of this severity.
+    /// Empty for the normal colour.
+    /// 
+    /// ```rust
+    /// # extern crate mhlog;
+    /// # use mhlog::Severity;
+    /// let sev = Severity::Err;
+    /// println!("{}{}\x1b[0m", sev.color_ansi_escape(), "Failed");
+    /// ```
+    pub fn color_ansi_escape(&self) -> &'static str {
+        use Severity::*;
         match self {
-            Err => pre.err(),
-            Warn => pre.warn(),
-            Info|Verbose => pre.info(),
-            Debug => pre.debug(),
+            Err => "\x1b[31m",
+            Warn => "\x1b[33m",
+            Info|Verbose => "",
+            Debug => "\x1b[2m",
+        }
+    }
+
+    #[cfg(feature = "colours")]
+    pub fn style(&self, txt: String) -> console::StyledObject<String> {
+        use console::style;
+        use Severity::*;
+
+        let obj = match self {
+            Err => style(txt).red(),
+            Warn => style(txt).yellow(),
+            Info => style(txt),
+            Verbose => VERBOSE_STYLE.read().unwrap().apply_to(txt),
+            Debug => style(txt).dim(),
+        };
+
+        let obj = if self.to_stderr() {
+            obj.for_stderr()
+        } else {
+            obj.for_stdout()
+        };
+
+        match *COLOR_MODE.read().unwrap() {
+            ColorMode::Always => obj.force_styling(true),
+            ColorMode::Never => obj.force_styling(false),
+            ColorMode::Auto => obj,
+        }
+    }
+
+    /// Whether messages of this severity are written to stderr.
+    /// See [`set_stderr`](fn.set_stderr.html).
+    pub fn to_stderr(&self) -> bool {
+        match *OUTPUT_TARGET.read().unwrap() {
+            OutputTarget::AllStderr => true,
+            OutputTarget::AllStdout => false,
+            OutputTarget::Mixed => STDERR_TABLE.read().unwrap()[self.index()],
+        }
+    }
+
+    /// The default of `to_stderr`, given by the `only_stderr` and
+    /// `only_stdout` features.
+    fn default_to_stderr(self) -> bool {
+        use Severity::*;
+        if cfg!(feature = "only_stderr") {
+            return true
+        }
+        if cfg!(feature = "only_stdout") {
+            return false
+        }
+        matches!(self, Err|Warn)
+    }
+
+    pub fn prefix(&self) -> String {
+        use Severity::*;
+        let key = if *self == Verbose { Info } else { *self };
+        if let Some(pre) = THREAD_PREFIX.with(|map| map.borrow().get(&key).cloned()) {
+            return pre
+        }
+        PREFIXES.read().unwrap().get(*self)
+    }
+
+    /// The severity messages of this severity are redirected to.
+    /// See [`redirect`](fn.redirect.html).
+    pub fn redirected(self) -> Severity {
+        let map = REDIRECTS.read().unwrap();
+        let mut sev = self;
+        while let Some(&to) = map.get(&sev) {
+            sev = to;
         }
+        sev
     }
 
     pub fn suppressed(&self) -> bool {
         use Severity::*;
+        if *self != Err && *self != Warn && *QUIET.read().unwrap() {
+            return true
+        }
+        if let Some(level) = env_level() {
+            return self.index() >= level as usize
+        }
+        if SUPPRESSED.read().unwrap()[self.index()] {
+            return true
+        }
+        if *self != Err && in_quiet_period() {
+            return true
+        }
         match self {
             Debug => !*DEBUG.read().unwrap(),
-            Verbose => !*VERBOSE.read().unwrap(),
+            Verbose => *VERBOSE_LEVEL.read().unwrap() == 0,
             _ => false,
         }
     }
 }
 
 
+// -----------------------------------------------------------------------------
+// Colours
+
+/// When to colour log messages. Requires the `colours` feature.
+#[cfg(feature = "colours")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Always colour messages, also when not writing to a terminal if
+    /// [`set_color_strip_on_file`] is disabled.
+    /// 
+    /// [`set_color_strip_on_file`]: fn.set_color_strip_on_file.html
+    Always,
+    /// Colour messages only when writing to a terminal (default).
+    Auto,
+    /// Never colour messages.
+    Never,
+}
+
+/// Change when log messages are coloured. Requires the `colours` feature.
+/// 
+/// By default messages are only coloured when writing to a terminal.
+/// 
+#[cfg(feature = "colours")]
+pub fn set_color_mode(mode: ColorMode) {
+    let mut m = COLOR_MODE.write().unwrap();
+    *m = mode;
+}
+
+/// Enable/disable removing ANSI codes from messages written to something
+/// other than a terminal: redirected stdout/stderr, writers added with
+/// [`add_writer`], and [`with_custom_writer`]. Requires the `colours`
+/// feature.
+/// 
+/// Enabled by default, so files don't get cluttered with escape codes,
+/// even with [`ColorMode::Always`] or colours added by a formatter.
+/// 
+/// [`add_writer`]: fn.add_writer.html
+/// [`with_custom_writer`]: fn.with_custom_writer.html
+/// [`ColorMode::Always`]: enum.ColorMode.html#variant.Always
+#[cfg(feature = "colours")]
+pub fn set_color_strip_on_file(enabled: bool) {
+    COLOR_STRIP_ON_FILE.store(enabled, Ordering::Relaxed);
+}
+
+/// Extended colour, set with `set_severity_color_256` or
+/// `set_severity_color_rgb`.
+#[cfg(feature = "colours")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExtColor {
+    Ansi256(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[cfg(feature = "colours")]
+impl ExtColor {
+    /// The escape code of the colour, downgraded to what the terminal
+    /// supports.
+    fn escape(self) -> String {
+        let (truecolor, c256) = *COLOR_SUPPORT;
+        match self {
+            ExtColor::Rgb(r, g, b) if truecolor => format!("\x1b[38;2;{};{};{}m", r, g, b),
+            ExtColor::Rgb(r, g, b) if c256 => format!("\x1b[38;5;{}m", rgb_to_256(r, g, b)),
+            ExtColor::Rgb(r, g, b) => format!("\x1b[{}m", 30 + rgb_to_8(r, g, b)),
+            ExtColor::Ansi256(i) if c256 => format!("\x1b[38;5;{}m", i),
+            ExtColor::Ansi256(i) => {
+                let (r, g, b) = ansi256_to_rgb(i);
+                format!("\x1b[{}m", 30 + rgb_to_8(r, g, b))
+            }
+        }
+    }
+}
+
+/// Nearest colour of the 6x6x6 colour cube of the 256 colour palette.
+#[cfg(feature = "colours")]
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |x: u8| ((x as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+/// Nearest of the basic 8 colours, as an offset from black.
+#[cfg(feature = "colours")]
+fn rgb_to_8(r: u8, g: u8, b: u8) -> u8 {
+    (r > 127) as u8 | ((g > 127) as u8) << 1 | ((b > 127) as u8) << 2
+}
+
+/// Approximate RGB value of a colour of the 256 colour palette.
+#[cfg(feature = "colours")]
+fn ansi256_to_rgb(i: u8) -> (u8, u8, u8) {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match i {
+        0..=15 => {
+            let v = if i >= 8 { 255 } else { 192 };
+            let c = i % 8;
+            (if c & 1 != 0 { v } else { 0 }, if c & 2 != 0 { v } else { 0 }, if c & 4 != 0 { v } else { 0 })
+        }
+        16..=231 => {
+            let n = i - 16;
+            (LEVELS[(n / 36) as usize], LEVELS[(n / 6 % 6) as usize], LEVELS[(n % 6) as usize])
+        }
+        _ => {
+            let v = 8 + (i - 232) * 10;
+            (v, v, v)
+        }
+    }
+}
+
+/// Colour messages of a severity with a colour of the 256 colour palette.
+/// Requires the `colours` feature.
+/// 
+/// If the terminal doesn't support 256 colours, according to the `TERM`
+/// and `COLORTERM` environment variables, the nearest basic colour is used.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// # #[cfg(feature = "colours")]
+/// mhlog::set_severity_color_256(Severity::Info, 39);
+/// ```
+#[cfg(feature = "colours")]
+pub fn set_severity_color_256(severity: Severity, color_index: u8) {
+    let mut colors = EXT_COLORS.write().unwrap();
+    colors.insert(severity, ExtColor::Ansi256(color_index));
+}
+
+/// Colour messages of a severity with a true colour RGB value.
+/// Requires the `colours` feature.
+/// 
+/// If the terminal doesn't support true colour, according to the
+/// `COLORTERM` environment variable, the nearest colour of the 256 colour
+/// palette or of the basic colours is used.
+#[cfg(feature = "colours")]
+pub fn set_severity_color_rgb(severity: Severity, r: u8, g: u8, b: u8) {
+    let mut colors = EXT_COLORS.write().unwrap();
+    colors.insert(severity, ExtColor::Rgb(r, g, b));
+}
+
+/// Change the style of verbose messages. Requires the `colours` feature.
+/// 
+/// By default verbose messages are cyan.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # #[cfg(feature = "colours")] {
+/// mhlog::set_verbose_style(console::Style::new().blue().italic());
+/// # }
+/// ```
+#[cfg(feature = "colours")]
+pub fn set_verbose_style(style: console::Style) {
+    let mut s = VERBOSE_STYLE.write().unwrap();
+    *s = style;
+}
+
+/// Change the ANSI escape code used to colour messages of a severity.
+/// Requires the `simple-colors` feature.
+/// 
+/// The escape code is written before the message, and reset with
+/// `\x1b[0m` after it. By default errors are red, warnings yellow, and
+/// debug messages dim. Unlike the `colours` feature, the escape codes are
+/// always written, also when not writing to a terminal.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::Severity;
+/// # #[cfg(feature = "simple-colors")]
+/// mhlog::set_ansi_color(Severity::Info, "\x1b[32m");
+/// ```
+/// 
+/// If the `colours` feature is also enabled it takes precedence, and the
+/// escape codes set here are not used.
+#[cfg(feature = "simple-colors")]
+pub fn set_ansi_color(severity: Severity, escape_code: &'static str) {
+    let mut map = ANSI_COLORS.write().unwrap();
+    map.insert(severity, escape_code);
+}
+
 // -----------------------------------------------------------------------------
 // Prefixes
 
+/// Case transformation applied to static prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PrefixCase {
+    /// Convert static prefixes to upper case.
+    Upper,
+    /// Convert static prefixes to lower case.
+    Lower,
+    /// Use static prefixes as they are (default).
+    #[default]
+    AsIs,
+}
+
+impl PrefixCase {
+    fn apply(self, s: &str) -> String {
+        match self {
+            PrefixCase::Upper => s.to_uppercase(),
+            PrefixCase::Lower => s.to_lowercase(),
+            PrefixCase::AsIs => s.to_string(),
+        }
+    }
+}
+
 /// Function signature for prefix generators.
 pub type PrefixFn = fn() -> String;
 
+/// Function signature for prefix generators used for all severities.
+pub type UniversalPrefixFn = fn(Severity) -> String;
+
+/// A previous prefix value: the dynamic prefix function, if any, and the
+/// static prefix string.
+pub type OldPrefix = (Option<PrefixFn>, String);
+
+/// A prefix template, with specifiers substituted for each message.
+/// 
+/// The supported specifiers are:
+/// 
+/// - `{level}`: the name of the severity, like `info`
+/// - `{time}`: the current UTC time, like `2024-05-17T09:41:07Z`
+/// - `{thread}`: the name of the current thread, or its ID if unnamed
+/// 
+/// Literal braces are written as `{{` and `}}`.
+/// 
+/// ```rust
+/// # extern crate mhlog;
+/// # use mhlog::{info, PrefixTemplate};
+/// let t = PrefixTemplate::new("[{level}] {time}").unwrap();
+/// mhlog::set_info_prefix_template(t);
+/// info!("Templated prefix");
+/// 
+/// assert!(PrefixTemplate::new("{host}").is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    Level,
+    Time,
+    Thread,
+}
+
+impl PrefixTemplate {
+    /// Parse a prefix template, failing on unknown or unterminated
+    /// specifiers.
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut lit = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    lit.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    lit.push('}');
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(TemplateError(format!("{{{}", spec))),
+                        }
+                    }
+                    let part = match spec.as_str() {
+                        "level" => TemplatePart::Level,
+                        "time" => TemplatePart::Time,
+                        "thread" => TemplatePart::Thread,
+                        _ => return Err(TemplateError(format!("{{{}}}", spec))),
+                    };
+                    if !lit.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut lit)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err(TemplateError("}".to_string())),
+                c => lit.push(c),
+            }
+        }
+        if !lit.is_empty() {
+            parts.push(TemplatePart::Literal(lit));
+        }
+        Ok(PrefixTemplate { parts })
+    }
+
+    fn render(&self, severity: Severity) -> String {
+        let mut s = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(lit) => s.push_str(lit),
+                TemplatePart::Level => s.push_str(&format!("{:?}", severity).to_lowercase()),
+                TemplatePart::Time => s.push_str(&utc_now()),
+                TemplatePart::Thread => {
+                    let t = std::thread::current();
+                    match t.name() {
+                        Some(name) => s.push_str(name),
+                        None => s.push_str(&format!("{:?}", t.id())),
+                    }
+                }
+            }
+        }
+        s
+    }
+}
+
+/// Error returned when parsing a prefix template with an unknown or
+/// malformed specifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError(String);
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid prefix template specifier: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// The current UTC time formatted as `YYYY-MM-DDTHH:MM:SSZ`.
+fn utc_now() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60,
+    )
+}
+
 /// LogPrefixes maintains the state of prefix values, either
 /// constant or generated for each message.
 /// 
-#[derive(Debug, Default)]
+#[derive(Default, Clone)]
 struct LogPrefixes {
     err_fn: Option<PrefixFn>,
     warn_fn: Option<PrefixFn>,
@@ -367,6 +6272,61 @@ struct LogPrefixes {
     warn_str: String,
     info_str: String,
     debug_str: String,
+    case: PrefixCase,
+    universal_fn: Option<UniversalPrefixFn>,
+    bracket: (&'static str, &'static str),
+    templates: HashMap<Severity, PrefixTemplate>,
+}
+
+/// Shows the current prefix of each severity, and whether it is static or
+/// dynamic:
+/// 
+/// ```text
+/// err:  "[!!]" (dynamic)
+/// warn: "[!]"  (static)
+/// info: "[*]"  (static)
+/// debug:"[~]"  (static)
+/// ```
+impl std::fmt::Display for LogPrefixes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use Severity::*;
+        let severities = [
+            (Err, "err:", self.err_fn),
+            (Warn, "warn:", self.warn_fn),
+            (Info, "info:", self.info_fn),
+            (Debug, "debug:", self.debug_fn),
+        ];
+        for (i, (sev, name, func)) in severities.iter().enumerate() {
+            let dynamic = func.is_some() || self.universal_fn.is_some() || self.templates.contains_key(sev);
+            let kind = if dynamic { "dynamic" } else { "static" };
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:<6}{:<6} ({})", name, format!("{:?}", self.get(*sev)), kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// Like the derived `Debug`, but with prefix functions shown by address.
+impl std::fmt::Debug for LogPrefixes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let addr = |func: Option<PrefixFn>| func.map(|func| func as *const ());
+        f.debug_struct("LogPrefixes")
+            .field("err_fn", &addr(self.err_fn))
+            .field("warn_fn", &addr(self.warn_fn))
+            .field("info_fn", &addr(self.info_fn))
+            .field("debug_fn", &addr(self.debug_fn))
+            .field("err_str", &self.err_str)
+            .field("warn_str", &self.warn_str)
+            .field("info_str", &self.info_str)
+            .field("debug_str", &self.debug_str)
+            .field("case", &self.case)
+            .field("universal_fn", &self.universal_fn.map(|func| func as *const ()))
+            .field("bracket", &self.bracket)
+            .field("templates", &self.templates)
+            .finish()
+    }
 }
 
 impl LogPrefixes {
@@ -376,35 +6336,65 @@ impl LogPrefixes {
             warn_str: "[!]".to_string(),
             info_str: "[*]".to_string(),
             debug_str: "[~]".to_string(),
+            bracket: ("[", "]"),
             ..Default::default()
         }
     }
 
+    /// The prefix function and string used for the given severity.
+    fn slots(&mut self, severity: Severity) -> (&mut Option<PrefixFn>, &mut String) {
+        use Severity::*;
+        match severity {
+            Err => (&mut self.err_fn, &mut self.err_str),
+            Warn => (&mut self.warn_fn, &mut self.warn_str),
+            Info|Verbose => (&mut self.info_fn, &mut self.info_str),
+            Debug => (&mut self.debug_fn, &mut self.debug_str),
+        }
+    }
+
+    /// The current prefix of the given severity.
+    pub fn get(&self, severity: Severity) -> String {
+        use Severity::*;
+        if let Some(f) = self.universal_fn {
+            return f(severity)
+        }
+        let key = if severity == Verbose { Info } else { severity };
+        if let Some(t) = self.templates.get(&key) {
+            return t.render(severity)
+        }
+        match severity {
+            Err => self.err(),
+            Warn => self.warn(),
+            Info|Verbose => self.info(),
+            Debug => self.debug(),
+        }
+    }
+
     pub fn err(&self) -> String {
         match self.err_fn {
             Some(f) => f(),
-            None => self.err_str.clone(),
+            None => self.case.apply(&self.err_str),
         }
     }
 
     pub fn warn(&self) -> String {
         match self.warn_fn {
             Some(f) => f(),
-            None => self.warn_str.clone(),
+            None => self.case.apply(&self.warn_str),
         }
     }
 
     pub fn info(&self) -> String {
         match self.info_fn {
             Some(f) => f(),
-            None => self.info_str.clone(),
+            None => self.case.apply(&self.info_str),
         }
     }
 
     pub fn debug(&self) -> String {
         match self.debug_fn {
             Some(f) => f(),
-            None => self.debug_str.clone(),
+            None => self.case.apply(&self.debug_str),
         }
     }
 }